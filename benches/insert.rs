@@ -0,0 +1,50 @@
+#[macro_use]
+extern crate criterion;
+use afeather::*;
+use criterion::{BatchSize, Criterion, Throughput};
+
+const ARCHETYPES: u32 = 10_000;
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash)]
+struct SourceId(u32);
+impl Component for SourceId {
+    type Storage = PerArchetype<Self>;
+}
+
+#[allow(dead_code)]
+struct Value(u64);
+impl Component for Value {
+    type Storage = PerEntity<Self>;
+}
+
+// A world with one entity in each of ARCHETYPES archetypes.
+fn populated_world() -> World {
+    let mut world = World::new();
+    for i in 0..ARCHETYPES {
+        world.add_entity(UniqueId(i as u128), (SourceId(i), Value(0)));
+    }
+    world
+}
+
+fn insert(c: &mut Criterion) {
+    let mut group = c.benchmark_group("insert");
+    group.throughput(Throughput::Elements(ARCHETYPES as u64));
+    group.sample_size(10);
+    group.bench_function("10k archetypes", |b| {
+        b.iter_batched(
+            populated_world,
+            |mut world| {
+                for i in 0..ARCHETYPES {
+                    let id = UniqueId((ARCHETYPES + i) as u128);
+                    world.add_entity(id, (SourceId(i), Value(i as u64)));
+                }
+                world
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, insert);
+criterion_main!(benches);
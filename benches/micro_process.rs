@@ -1,15 +1,104 @@
-#[macro_use]
-extern crate criterion;
-use criterion::{Criterion, BenchmarkId};
-
-
-// TODO: Compare Process to ProcessSimple for an easy case with straightforward types
-fn macro_process() {
-
-}
-
-// TODO: Add another which compares Process and ProcessSimple for a complex type, eg: with Option
-
-
-criterion_group!(benches, macro_process);
-criterion_main!(benches);
+#[macro_use]
+extern crate criterion;
+use afeather::*;
+use criterion::{Criterion, Throughput};
+
+const ENTITIES: u32 = 100_000;
+const SOURCES: u32 = 16;
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash)]
+struct SourceId(u32);
+impl Component for SourceId {
+    type Storage = PerArchetype<Self>;
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash)]
+struct Scale(u64);
+impl Component for Scale {
+    type Storage = PerArchetype<Self>;
+}
+
+#[derive(Copy, Clone)]
+struct Value(u64);
+impl Component for Value {
+    type Storage = PerEntity<Self>;
+}
+
+#[allow(dead_code)]
+#[derive(Copy, Clone)]
+struct Doubled(u64);
+impl Component for Doubled {
+    type Storage = PerEntity<Self>;
+}
+
+// Half of the sources have a Scale, so that Option<Scale> is sometimes None.
+fn populated_world() -> World {
+    let mut world = World::new();
+    for i in 0..ENTITIES {
+        let source = SourceId(i % SOURCES);
+        let id = UniqueId(i as u128);
+        if source.0 < SOURCES / 2 {
+            world.add_entity(id, (source, Value(i as u64), Doubled(0)));
+        } else {
+            world.add_entity(id, (source, Scale(3), Value(i as u64), Doubled(0)));
+        }
+    }
+    world
+}
+
+struct Double {}
+impl Process for Double {
+    type Reads = Value;
+    type Writes = Doubled;
+    fn execute(&self, read: &[Value], write: &mut [Doubled]) {
+        for (value, doubled) in read.iter().zip(write.iter_mut()) {
+            *doubled = Doubled(value.0 * 2);
+        }
+    }
+}
+
+struct DoubleSimple {}
+impl ProcessSimple for DoubleSimple {
+    type Reads = Value;
+    type Writes = Doubled;
+    fn execute(&self, read: &Value) -> Doubled {
+        Doubled(read.0 * 2)
+    }
+}
+
+struct DoubleScaled {}
+impl Process for DoubleScaled {
+    type Reads = (Value, Option<Scale>);
+    type Writes = Doubled;
+    fn execute(&self, (values, scale): (&[Value], Option<&Scale>), write: &mut [Doubled]) {
+        let scale = scale.map_or(1, |s| s.0);
+        for (value, doubled) in values.iter().zip(write.iter_mut()) {
+            *doubled = Doubled(value.0 * 2 * scale);
+        }
+    }
+}
+
+struct DoubleScaledSimple {}
+impl ProcessSimple for DoubleScaledSimple {
+    type Reads = (Value, Option<Scale>);
+    type Writes = Doubled;
+    fn execute(&self, (value, scale): (&Value, Option<&Scale>)) -> Doubled {
+        Doubled(value.0 * 2 * scale.map_or(1, |s| s.0))
+    }
+}
+
+fn micro_process(c: &mut Criterion) {
+    let mut group = c.benchmark_group("micro_process");
+    group.throughput(Throughput::Elements(ENTITIES as u64));
+    let mut world = populated_world();
+    group.bench_function("Process", |b| b.iter(|| world.execute_process(&Double {})));
+    group.bench_function("ProcessSimple", |b| b.iter(|| world.execute_process_simple(&DoubleSimple {})));
+    group.bench_function("Process with Option", |b| b.iter(|| world.execute_process(&DoubleScaled {})));
+    group.bench_function("ProcessSimple with Option", |b| {
+        b.iter(|| world.execute_process_simple(&DoubleScaledSimple {}))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, micro_process);
+criterion_main!(benches);
@@ -1,19 +1,20 @@
 use crate::*;
-use std::rc::Rc;
-use unordered_hash::UnorderedHasher;
+use std::any::TypeId;
+use std::marker::PhantomData;
+use crate::sync::Shared;
 
 pub struct Archetype {
     num_entities: usize,
     components: Components,
-	requirements: u64,
+	key: ArchetypeKey,
 }
 
 impl Archetype {
-    pub fn new(requirements: u64) -> Self {
+    pub fn new(key: ArchetypeKey) -> Self {
         Self {
             num_entities: 0,
             components: Components::new(),
-			requirements
+			key
         }
     }
 
@@ -21,7 +22,13 @@ impl Archetype {
         self.num_entities
     }
 
+    /// Whether the archetype has CAPACITY entities. Further entities go into another chunk of the archetype.
+    pub fn is_full(&self) -> bool {
+        self.num_entities >= CAPACITY
+    }
+
     pub fn entity_write_slot(&mut self) -> usize {
+        debug_assert!(!self.is_full());
         let result = self.num_entities;
         self.num_entities += 1;
         result
@@ -33,11 +40,68 @@ impl Archetype {
 		self.components.remove_entity(index, top);
 	}
 
-    pub fn get_storage<T: AnyStorage>(&self) -> Option<Rc<T>> {
+	/// Moves the entity at index into destination_index of the destination archetype, except for the
+	/// storage with the excluded TypeId. The caller is responsible for taking the excluded value
+	/// using the returned top index.
+	pub fn move_entity(&mut self, index: usize, destination: &mut Archetype, destination_index: usize, exclude: TypeId) -> usize {
+		self.num_entities -= 1;
+		let top = self.num_entities;
+		for (type_id, storage) in self.components.any.iter() {
+			if *type_id != exclude {
+				storage.move_entity(index, top, destination, destination_index);
+			}
+		}
+		top
+	}
+
+	/// Removes the entity at index, returning the components of it which are in T.
+	/// Returns None if the entity does not have every component in T.
+	pub fn take_entity<T: EntityReader>(&mut self, index: usize) -> Option<T> {
+		let mut taken = Vec::new();
+		T::add_storage_types(&mut taken);
+		if !taken.iter().all(|t| self.components.any.get(&t.id).is_some_and(|s| s.contains(index))) {
+			return None;
+		}
+		self.num_entities -= 1;
+		let top = self.num_entities;
+		for (type_id, storage) in self.components.any.iter() {
+			if !taken.iter().any(|t| t.id == *type_id) {
+				storage.remove_entity(index, top);
+			}
+		}
+		T::take(self, index, top)
+	}
+
+	/// The storages which have a value for the entity at index, named by their component, in sorted order.
+	pub fn storage_types(&self, index: usize) -> Vec<StorageType> {
+		let mut result: Vec<_> = self
+			.components
+			.any
+			.iter()
+			.filter(|(_, storage)| storage.contains(index))
+			.map(|(type_id, storage)| StorageType {
+				id: *type_id,
+				name: storage.component_name(),
+			})
+			.collect();
+		result.sort();
+		result
+	}
+
+	/// Adds the requirements of each storage except for the one with the excluded TypeId.
+	pub fn add_archetype_requirements(&self, requirements: &mut ArchetypeRequirements, exclude: TypeId) {
+		for (type_id, storage) in self.components.any.iter() {
+			if *type_id != exclude {
+				storage.add_requirements(requirements);
+			}
+		}
+	}
+
+    pub fn get_storage<T: AnyStorage>(&self) -> Option<Shared<T>> {
         self.components.get_storage::<T>()
     }
 
-    pub fn get_storage_mut<T: AnyStorage>(&self) -> Option<Rc<T>> {
+    pub fn get_storage_mut<T: AnyStorage>(&self) -> Option<Shared<T>> {
         self.components.get_storage_mut::<T>()
     }
 
@@ -45,13 +109,24 @@ impl Archetype {
         self.components.add(storage);
     }
 
-	pub fn get_requirements(&self) -> u64 {
-		self.requirements
+	pub fn get_key(&self) -> &ArchetypeKey {
+		&self.key
 	}
 
 	pub fn components(&self) -> &Components {
 		&self.components
 	}
+
+	pub fn components_mut(&mut self) -> &mut Components {
+		&mut self.components
+	}
+
+	/// Stamps every storage of the archetype as modified at the version.
+	pub fn set_version(&self, version: Version) {
+		for storage in self.components.any.values() {
+			storage.set_version(version);
+		}
+	}
 }
 
 pub trait ArchetypeInitializer {
@@ -62,9 +137,93 @@ pub trait ArchetypeFilter {
     fn includes(&self, archetype: &Archetype) -> bool;
 }
 
+/// Includes archetypes with storage for T. Sparse storage may exist without any values.
+pub struct With<T>(PhantomData<T>);
+
+/// Excludes archetypes with storage for T.
+pub struct Without<T>(PhantomData<T>);
+
+/// Includes archetypes with storage for any of the components in the tuple T.
+pub struct AnyOf<T>(PhantomData<T>);
+
+impl<T> Default for With<T> {
+	fn default() -> Self {
+		Self(PhantomData)
+	}
+}
+
+impl<T> Default for Without<T> {
+	fn default() -> Self {
+		Self(PhantomData)
+	}
+}
+
+impl<T> Default for AnyOf<T> {
+	fn default() -> Self {
+		Self(PhantomData)
+	}
+}
+
+/// Includes archetypes where the PerArchetype value of T matches the predicate. The value is read
+/// from the archetype's key, so no storage is borrowed.
+pub struct Where<T, P = fn(&T) -> bool> {
+	// None includes every archetype with a value for T.
+	predicate: Option<P>,
+	_marker: PhantomData<T>,
+}
+
+impl<T, P: Fn(&T) -> bool> Where<T, P> {
+	pub fn new(predicate: P) -> Self {
+		Self {
+			predicate: Some(predicate),
+			_marker: PhantomData,
+		}
+	}
+}
+
+impl<T, P> Default for Where<T, P> {
+	fn default() -> Self {
+		Self {
+			predicate: None,
+			_marker: PhantomData,
+		}
+	}
+}
+
+impl<T: Component<Storage = PerArchetype<T>> + Eq, P: Fn(&T) -> bool> ArchetypeFilter for Where<T, P> {
+	#[inline]
+	fn includes(&self, archetype: &Archetype) -> bool {
+		match archetype.get_key().value::<T>() {
+			Some(value) => self.predicate.as_ref().is_none_or(|predicate| predicate(value)),
+			None => false,
+		}
+	}
+}
+
+impl<T: Component> ArchetypeFilter for With<T> {
+	#[inline]
+	fn includes(&self, archetype: &Archetype) -> bool {
+		archetype.components().contains::<T::Storage>()
+	}
+}
+
+impl<T: Component> ArchetypeFilter for Without<T> {
+	#[inline]
+	fn includes(&self, archetype: &Archetype) -> bool {
+		!archetype.components().contains::<T::Storage>()
+	}
+}
+
+pub trait EntityReader: Sized {
+    /// Adds the TypeId of the storage of each component, named by the component.
+    fn add_storage_types(types: &mut Vec<StorageType>);
+    /// Takes the components of the entity at index, moving top into it's place as AnyStorage::remove_entity does.
+    fn take(archetype: &Archetype, index: usize, top: usize) -> Option<Self>;
+}
+
 pub trait EntityWriter {
     fn write(self, archetype: &mut Archetype, index: usize);
-	fn add_archetype_requirements(&self, hasher: &mut UnorderedHasher);
+	fn add_archetype_requirements(&self, requirements: &mut ArchetypeRequirements);
 }
 
 // TODO: The design here uses a static method, because we want to be able to simply specify the read/write
@@ -72,7 +231,8 @@ pub trait EntityWriter {
 // execute take the world and use some kind of filter builder. That would allow, eg: specifying per-archetype
 // components having a particular value or the like. The slight distinction that's interesting to performance
 // for that case is that the archetype and components would not need to be borrowed just to be filtered out,
-// allowing a higher degree of parallelism. This concern does not outweigh simplicity for now.
+// allowing a higher degree of parallelism. The filter of a query or process (eg: Where) covers this case by
+// only looking at the archetype's key, so the static method remains for reads.
 
 
 impl<T: ReadableStorage> ReadableStorage for Option<T> {
@@ -81,18 +241,54 @@ impl<T: ReadableStorage> ReadableStorage for Option<T> {
     fn get(world_storage: &Components, archetype_storage: &Components) -> Option<Self::Read> {
         Some(T::get(world_storage, archetype_storage))
     }
+    #[inline(always)]
+    fn add_read_types(types: &mut Vec<StorageType>) {
+        T::add_read_types(types)
+    }
+    #[inline(always)]
+    fn changed_since(read: &Self::Read, since: Version) -> bool {
+        read.as_ref().is_none_or(|read| T::changed_since(read, since))
+    }
+}
+
+impl<T: ReadableStorageOwned> ReadableStorageOwned for Option<T> {
+    type Owned = Option<T::Owned>;
+    #[inline(always)]
+    fn read_owned(read: &Self::Read, index: usize) -> Option<Self::Owned> {
+        Some(read.as_ref().and_then(|read| T::read_owned(read, index)))
+    }
+}
+
+/// Reads T, but only from archetypes where T has been modified since the version given by
+/// Query::changed_since or Process::changed_since. Other archetypes are skipped entirely.
+pub struct Changed<T>(PhantomData<T>);
+
+impl<T: ReadableStorage> ReadableStorage for Changed<T> {
+    type Read = T::Read;
+    #[inline(always)]
+    fn get(world_storage: &Components, archetype_storage: &Components) -> Option<Self::Read> {
+        T::get(world_storage, archetype_storage)
+    }
+    #[inline(always)]
+    fn add_read_types(types: &mut Vec<StorageType>) {
+        T::add_read_types(types)
+    }
+    fn changed_since(read: &Self::Read, since: Version) -> bool {
+        read.borrow().version() > since && T::changed_since(read, since)
+    }
 }
 
 impl<T: RefLike> RefLike for Option<T> {
-    type Borrowed = Option<T::Borrowed>;
-    fn borrow(&self) -> Self::Borrowed {
+    type Borrowed<'a> = Option<T::Borrowed<'a>>;
+    fn borrow(&self) -> Self::Borrowed<'_> {
         self.as_ref().map(|v| v.borrow())
     }
 }
 
 impl<T: BorrowedStorage> BorrowedStorage for Option<T> {
-    type Item = Option<T::Item>;
-    type Batch = Option<T::Batch>;
+    type Item<'a> = Option<T::Item<'a>> where Self: 'a;
+    type Batch<'a> = Option<T::Batch<'a>> where Self: 'a;
+    type EntityItem<'a> = Option<T::Item<'a>> where Self: 'a;
     #[inline(always)]
     fn version(&self) -> Version {
         match self {
@@ -100,14 +296,73 @@ impl<T: BorrowedStorage> BorrowedStorage for Option<T> {
             None => Version(0),
         }
     }
-    fn read(&self, index: usize) -> Option<Self::Item> {
-        self.as_ref().map(|s| s.read(index))
+    fn read(&self, index: usize) -> Option<Self::Item<'_>> {
+        Some(self.as_ref().and_then(|s| s.read(index)))
     }
-    fn read_batch(&self) -> Self::Batch {
+    fn read_entity(&self, index: usize) -> Self::EntityItem<'_> {
+        self.as_ref().and_then(|s| s.read(index))
+    }
+    fn read_batch(&self) -> Self::Batch<'_> {
         self.as_ref().map(|s| s.read_batch())
     }
 }
 
+impl<T: WritableStorage> WritableStorage for Option<T> {
+    type ReadMut = Option<T::ReadMut>;
+    #[inline(always)]
+    fn get_mut(world_storage: &Components, archetype: &Archetype) -> Option<Self::ReadMut> {
+        Some(T::get_mut(world_storage, archetype))
+    }
+    fn add_write_types(types: &mut Vec<StorageType>) {
+        T::add_write_types(types)
+    }
+    // Does not prepare T, so that only archetypes which already have T are written.
+}
+
+impl<T: RefLikeMut> RefLikeMut for Option<T> {
+    type ItemMut = Option<T::ItemMut>;
+    type BorrowedMut<'a> = Option<T::BorrowedMut<'a>>;
+    fn borrow_mut(&self) -> Self::BorrowedMut<'_> {
+        self.as_ref().map(|v| v.borrow_mut())
+    }
+    fn current_as_of(&self) -> Version {
+        match self {
+            Some(storage) => storage.current_as_of(),
+            None => Version(u64::MAX),
+        }
+    }
+}
+
+impl<T: BorrowedStorageMut> BorrowedStorageMut for Option<T> {
+    type ItemMut = Option<T::ItemMut>;
+    type BatchMut<'a> = Option<T::BatchMut<'a>> where Self: 'a;
+    /// Items written to a storage which is not there are dropped.
+    fn write(&mut self, index: usize, item: Self::ItemMut) {
+        if let (Some(storage), Some(item)) = (self, item) {
+            storage.write(index, item);
+        }
+    }
+    fn write_batch(&mut self) -> Self::BatchMut<'_> {
+        self.as_mut().map(|s| s.write_batch())
+    }
+    fn set_version(&mut self, version: Version) {
+        if let Some(storage) = self {
+            storage.set_version(version);
+        }
+    }
+    fn current_as_of(&self) -> Version {
+        match self {
+            Some(storage) => storage.current_as_of(),
+            None => Version(u64::MAX),
+        }
+    }
+    fn set_current_as_of(&mut self, version: Version) {
+        if let Some(storage) = self {
+            storage.set_current_as_of(version);
+        }
+    }
+}
+
 impl<T: ComponentWrite> ComponentWrite for Option<T> {
     type BatchWrite = Option<T::BatchWrite>;
     fn read_batch_mut(archetype: &Archetype) -> Option<Self::BatchWrite> {
@@ -0,0 +1,94 @@
+use crate::*;
+
+trait Command: FnOnce(&mut World) + Storable {}
+impl<T: FnOnce(&mut World) + Storable> Command for T {}
+
+/// Changes to the world which are queued while the world is borrowed, eg: by a process which writes
+/// Global<Commands>. The World starts with a Global<Commands>, and applies the queued changes in the
+/// order they were queued after each process or update (see World::apply_commands). Commands for an
+/// entity which was removed before they are applied are ignored, as is adding an entity with a
+/// UniqueId that is already taken.
+pub struct Commands {
+    queue: Vec<Box<dyn Command>>,
+}
+
+impl Default for Commands {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl Commands {
+    pub fn new() -> Self {
+        Self { queue: Vec::new() }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Queues any change to the world.
+    pub fn push(&mut self, command: impl FnOnce(&mut World) + Storable) {
+        self.queue.push(Box::new(command));
+    }
+
+    pub fn add_entity<T: EntityWriter + ArchetypeInitializer + Storable>(&mut self, unique_id: UniqueId, entity: T) {
+        self.push(move |world| {
+            let _ = world.try_add_entity(unique_id, entity);
+        });
+    }
+
+    pub fn remove_entity(&mut self, unique_id: UniqueId) {
+        self.push(move |world| {
+            let _ = world.try_remove_entity(unique_id);
+        });
+    }
+
+    pub fn insert_component<T: Component + EntityWriter + ArchetypeInitializer>(&mut self, unique_id: UniqueId, component: T)
+    where
+        T::Storage: EntityReaderFromComponentStorage<Component = T>,
+    {
+        self.push(move |world| {
+            let _ = world.try_insert_component(unique_id, component);
+        });
+    }
+
+    pub fn remove_component<T: Component>(&mut self, unique_id: UniqueId)
+    where
+        T::Storage: EntityReaderFromComponentStorage<Component = T>,
+    {
+        self.push(move |world| {
+            world.remove_component::<T>(unique_id);
+        });
+    }
+
+    pub fn insert_global<T: Storable>(&mut self, value: T) {
+        self.push(move |world| {
+            world.insert_global(value);
+        });
+    }
+
+    pub fn remove_global<T: Storable>(&mut self) {
+        self.push(|world| {
+            world.remove_global::<T>();
+        });
+    }
+
+    /// Queues the commands of other after those of self.
+    #[cfg(feature = "parallel")]
+    pub(crate) fn append(&mut self, other: Commands) {
+        self.queue.extend(other.queue);
+    }
+
+    pub(crate) fn apply(self, world: &mut World) {
+        for command in self.queue {
+            command(world);
+        }
+    }
+}
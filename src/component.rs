@@ -1,6 +1,6 @@
 use crate::*;
 
-pub trait Component: 'static {
+pub trait Component: Storable {
     type Storage: ReadableStorage + AnyStorage;
 }
 
@@ -10,11 +10,35 @@ impl<R: ReadableStorage + AnyStorage, T: Component<Storage = R>> ReadableStorage
     fn get(world_storage: &Components, archetype_storage: &Components) -> Option<Self::Read> {
         R::get(world_storage, archetype_storage)
     }
+    #[inline(always)]
+    fn add_read_types(types: &mut Vec<StorageType>) {
+        R::add_read_types(types)
+    }
+    #[inline(always)]
+    fn changed_since(read: &Self::Read, since: Version) -> bool {
+        R::changed_since(read, since)
+    }
+}
+
+impl<R: ReadableStorageOwned + AnyStorage, T: Component<Storage = R>> ReadableStorageOwned for T {
+    type Owned = R::Owned;
+    #[inline(always)]
+    fn read_owned(read: &Self::Read, index: usize) -> Option<Self::Owned> {
+        R::read_owned(read, index)
+    }
 }
 
 impl<W: WritableStorage + AnyStorage, T: Component<Storage = W>> WritableStorage for T {
     type ReadMut = W::ReadMut;
-    fn get_mut(archetype: &Archetype) -> Option<Self::ReadMut> {
-        W::get_mut(archetype)
+    fn get_mut(world_storage: &Components, archetype: &Archetype) -> Option<Self::ReadMut> {
+        W::get_mut(world_storage, archetype)
+    }
+    #[inline(always)]
+    fn add_write_types(types: &mut Vec<StorageType>) {
+        W::add_write_types(types)
+    }
+    #[inline(always)]
+    fn prepare(archetype: &mut Archetype) {
+        W::prepare(archetype)
     }
 }
@@ -0,0 +1,37 @@
+use crate::*;
+
+/// A process which produces a derived component, stored on the World by the TypeId of the component.
+pub(crate) trait Derive: Storable {
+    /// Runs the process for the archetype if any of it's Reads are newer than the current_as_of of it's Writes.
+    fn update(&self, world_storage: &Components, archetype: &Archetype, version: Version);
+    /// The access of the process, so that systems which read the component are ordered after those
+    /// which write it's inputs.
+    fn access(&self) -> Access;
+}
+
+pub(crate) struct Derived<P: Process>(pub P);
+
+impl<P: Process + Storable> Derive for Derived<P> {
+    fn update(&self, world_storage: &Components, archetype: &Archetype, version: Version) {
+        if !self.0.filter().includes(archetype) {
+            return;
+        }
+        if let Some(read) = P::Reads::get(world_storage, archetype.components()) {
+            if let Some(write) = P::Writes::get_mut(world_storage, archetype) {
+                let read_borrow = read.borrow();
+                // The Writes are only borrowed mutably when they are out of date, so that an
+                // up to date derived component may be borrowed while other components are read.
+                if read_borrow.version() > write.current_as_of() {
+                    let mut write_borrow = write.borrow_mut();
+                    write_borrow.set_version(version);
+                    write_borrow.set_current_as_of(version);
+                    self.0.execute(read_borrow.read_batch(), write_borrow.write_batch());
+                }
+            }
+        }
+    }
+
+    fn access(&self) -> Access {
+        Access::of::<P::Reads, P::Writes>()
+    }
+}
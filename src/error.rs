@@ -0,0 +1,51 @@
+use crate::*;
+use std::fmt;
+
+/// The reasons that the try_ methods of the World can fail. Components are named by their type.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// An entity with the UniqueId already exists.
+    DuplicateEntity(UniqueId),
+    /// There is no entity with the UniqueId.
+    EntityNotFound(UniqueId),
+    /// The components given for the entity include the same component more than once.
+    DuplicateComponent { entity: UniqueId, component: &'static str },
+    /// The entity does not have the component.
+    ComponentNotFound { entity: UniqueId, component: &'static str },
+    /// The components requested by World::take_entity are not exactly those of the entity. Names the
+    /// components which were requested twice, requested but missing, or present but not requested.
+    ComponentsMismatch { entity: UniqueId, components: Vec<&'static str> },
+    /// The UniqueId of an existing entity can not be inserted as a component.
+    UniqueIdReplaced(UniqueId),
+    /// A process writes the same component more than once.
+    WrittenTwice { component: &'static str },
+    /// A process both reads and writes the same component.
+    ReadAndWritten { component: &'static str },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::DuplicateEntity(entity) => write!(f, "An entity with {:?} already exists", entity),
+            Error::EntityNotFound(entity) => write!(f, "No entity with {:?}", entity),
+            Error::DuplicateComponent { entity, component } => {
+                write!(f, "The entity {:?} has {} more than once", entity, component)
+            }
+            Error::ComponentNotFound { entity, component } => {
+                write!(f, "The entity {:?} does not have {}", entity, component)
+            }
+            Error::ComponentsMismatch { entity, components } => {
+                write!(f, "The components are not those of the entity {:?}: {}", entity, components.join(", "))
+            }
+            Error::UniqueIdReplaced(entity) => write!(f, "The UniqueId of the entity {:?} cannot be replaced", entity),
+            Error::WrittenTwice { component } => {
+                write!(f, "The same component is written more than once: {}", component)
+            }
+            Error::ReadAndWritten { component } => {
+                write!(f, "The same component is both read and written: {}", component)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
@@ -0,0 +1,81 @@
+use crate::sync::{RefCell, Shared};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+
+struct Slot<T> {
+    output: Option<T>,
+    waker: Option<Waker>,
+}
+
+/// The output of a query scheduled with World::schedule_query. The future completes when the query
+/// is run by World::run, so awaiting it before then from the same thread never completes.
+pub struct QueryHandle<T> {
+    slot: Shared<RefCell<Slot<T>>>,
+}
+
+impl<T> QueryHandle<T> {
+    pub(crate) fn new() -> Self {
+        let slot = Slot {
+            output: None,
+            waker: None,
+        };
+        Self {
+            slot: Shared::new(RefCell::new(slot)),
+        }
+    }
+
+    /// The function which completes the handle, to be called once with the output of the query.
+    pub(crate) fn completer(&self) -> impl FnMut(T) {
+        let slot = self.slot.clone();
+        move |output| {
+            let mut slot = slot.borrow_mut();
+            slot.output = Some(output);
+            if let Some(waker) = slot.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Takes the output if the query has run, without waiting.
+    pub fn try_take(&mut self) -> Option<T> {
+        self.slot.borrow_mut().output.take()
+    }
+}
+
+impl<T> Future for QueryHandle<T> {
+    type Output = T;
+    fn poll(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<T> {
+        let mut slot = self.slot.borrow_mut();
+        match slot.output.take() {
+            Some(output) => Poll::Ready(output),
+            None => {
+                slot.waker = Some(context.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Runs the future to completion on the current thread, for when there is no other executor.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = Box::pin(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut context = Context::from_waker(&waker);
+    loop {
+        match future.as_mut().poll(&mut context) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
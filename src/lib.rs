@@ -3,6 +3,8 @@
 #[macro_use]
 extern crate downcast_rs;
 
+pub mod sync;
+pub use sync::Storable;
 mod storage;
 pub use storage::*;
 mod tuples;
@@ -12,12 +14,24 @@ mod component;
 pub use component::*;
 mod archetype;
 pub use archetype::*;
+mod requirements;
+pub use requirements::*;
 mod query;
 pub use query::*;
 mod update;
 pub use update::*;
 mod process;
 pub use process::*;
+mod derived;
+use derived::*;
+mod commands;
+pub use commands::*;
+mod schedule;
+pub use schedule::*;
+mod executor;
+pub use executor::*;
+mod error;
+pub use error::*;
 
 #[cfg(test)]
 mod tests;
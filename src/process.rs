@@ -7,9 +7,19 @@ pub trait Process {
 
     fn execute(
         &self,
-        read: <<<<Self as Process>::Reads as ReadableStorage>::Read as RefLike>::Borrowed as BorrowedStorage>::Batch,
-        write: <<<<Self as Process>::Writes as WritableStorage>::ReadMut as RefLikeMut>::BorrowedMut as BorrowedStorageMut>::BatchMut,
+        read: <<<<Self as Process>::Reads as ReadableStorage>::Read as RefLike>::Borrowed<'_> as BorrowedStorage>::Batch<'_>,
+        write: <<<<Self as Process>::Writes as WritableStorage>::ReadMut as RefLikeMut>::BorrowedMut<'_> as BorrowedStorageMut>::BatchMut<'_>,
     );
+
+    /// Archetypes not included by the filter are skipped without borrowing any storage. Includes every archetype unless overridden.
+    #[inline(always)]
+    fn filter(&self) -> impl ArchetypeFilter {}
+
+    /// The version that Changed<T> reads compare against.
+    #[inline(always)]
+    fn changed_since(&self) -> Version {
+        Version(0)
+    }
 }
 
 pub trait ProcessSimple {
@@ -17,8 +27,8 @@ pub trait ProcessSimple {
     type Writes: WritableStorage;
 
     fn execute(&self,
-		read: <<<<Self as ProcessSimple>::Reads as ReadableStorage>::Read as RefLike>::Borrowed as BorrowedStorage>::Item)
-		-> <<<<Self as ProcessSimple>::Writes as WritableStorage>::ReadMut as RefLikeMut>::BorrowedMut as BorrowedStorageMut>::ItemMut;
+		read: <<<<Self as ProcessSimple>::Reads as ReadableStorage>::Read as RefLike>::Borrowed<'_> as BorrowedStorage>::Item<'_>)
+		-> <<<Self as ProcessSimple>::Writes as WritableStorage>::ReadMut as RefLikeMut>::ItemMut;
 }
 
 /*
@@ -1,56 +1,100 @@
 pub use crate::*;
-use std::marker::PhantomData;
 
 pub trait Query {
     type Reads: ReadableStorage;
     type Output;
 
     fn execute(&self, data: QueryData<Self::Reads>) -> Self::Output;
+
+    /// Archetypes not included by the filter are skipped without borrowing any storage. Includes every archetype unless overridden.
+    #[inline(always)]
+    fn filter(&self) -> impl ArchetypeFilter {}
+
+    /// The version that Changed<T> reads compare against.
+    #[inline(always)]
+    fn changed_since(&self) -> Version {
+        Version(0)
+    }
 }
 
-pub struct QueryData<'a, T> {
-    i: usize,
-    archetypes: &'a [Option<Archetype>],
-    globals: &'a Components,
-    _marker: PhantomData<&'a [T]>,
+/// The storages read by a query, borrowed for the whole of Query::execute. Iterates over the batch
+/// of each archetype, which can not outlive the borrow.
+pub struct QueryData<'a, 's, T: ReadableStorage> {
+    borrows: std::slice::Iter<'a, (<T::Read as RefLike>::Borrowed<'s>, usize)>,
 }
 
-impl<'a, T> QueryData<'a, T> {
-    pub fn new(globals: &'a Components, archetypes: &'a [Option<Archetype>]) -> Self {
-        Self {
-            i: 0,
-            archetypes,
-            globals,
-            _marker: PhantomData,
+/// Borrows the storages of each archetype that the query includes, and passes them to f.
+pub(crate) fn with_query_data<T: ReadableStorage, R>(
+    globals: &Components,
+    archetypes: &[Option<Archetype>],
+    filter: &dyn ArchetypeFilter,
+    since: Version,
+    f: impl FnOnce(QueryData<'_, '_, T>) -> R,
+) -> R {
+    let storages: Vec<(T::Read, usize)> = archetypes
+        .iter()
+        .flatten()
+        .filter(|archetype| filter.includes(archetype))
+        .filter_map(|archetype| {
+            let storage = T::get(globals, archetype.components())?;
+            if !T::changed_since(&storage, since) {
+                return None;
+            }
+            Some((storage, archetype.num_entities()))
+        })
+        .collect();
+    let borrows: Vec<_> = storages.iter().map(|(storage, len)| (storage.borrow(), *len)).collect();
+    f(QueryData { borrows: borrows.iter() })
+}
+
+impl<'a, 's, T: ReadableStorage> QueryData<'a, 's, T> {
+    /// Iterates over each entity instead of each archetype. Sparse components are given as Option<&T>
+    /// for every entity, whether or not the value is set.
+    pub fn entities(self) -> Entities<'a, 's, T> {
+        Entities {
+            borrow: None,
+            index: 0,
+            len: 0,
+            borrows: self.borrows,
         }
     }
 }
 
-impl<'a, RL: RefLike<Borrowed = B>, T: ReadableStorage<Read = RL>, B: BorrowedStorage> Iterator
-    for QueryData<'a, T>
-{
-    type Item = B::Batch;
+impl<'a, 's, T: ReadableStorage> Iterator for QueryData<'a, 's, T> {
+    type Item = <<T::Read as RefLike>::Borrowed<'s> as BorrowedStorage>::Batch<'a>;
     fn next(&mut self) -> Option<Self::Item> {
-        //self.borrow = None;
-        //self.storage = None;
-        while self.i < self.archetypes.len() {
-            let i = self.i;
-            self.i += 1;
-            if let Some(candidate) = &self.archetypes[i] {
-                let storage = T::get(self.globals, candidate.components());
-                if let Some(storage) = storage {
-                    //self.storage = Some(storage);
-                    let borrow = storage.borrow();
-                    //self.borrow = Some(borrow);
-                    let batch = borrow.read_batch();
-                    return Some(batch);
-                }
-            }
-        }
-        None
+        let (borrow, _) = self.borrows.next()?;
+        Some(borrow.read_batch())
     }
 
     fn size_hint(&self) -> (usize, Option<usize>) {
-        (0, Some(self.archetypes.len() - self.i))
+        self.borrows.size_hint()
+    }
+}
+
+pub struct Entities<'a, 's, T: ReadableStorage> {
+    borrow: Option<&'a <T::Read as RefLike>::Borrowed<'s>>,
+    index: usize,
+    len: usize,
+    borrows: std::slice::Iter<'a, (<T::Read as RefLike>::Borrowed<'s>, usize)>,
+}
+
+impl<'a, 's, T: ReadableStorage> Iterator for Entities<'a, 's, T> {
+    type Item = <<T::Read as RefLike>::Borrowed<'s> as BorrowedStorage>::EntityItem<'a>;
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(borrow) = self.borrow {
+                if self.index < self.len {
+                    let index = self.index;
+                    self.index += 1;
+                    return Some(borrow.read_entity(index));
+                }
+            }
+            let (borrow, len) = self.borrows.next()?;
+            self.borrow = Some(borrow);
+            self.index = 0;
+            self.len = *len;
+        }
     }
 }
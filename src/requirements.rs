@@ -0,0 +1,131 @@
+use crate::sync::{Shared, Storable};
+use downcast_rs::Downcast;
+use std::any::{type_name, TypeId};
+use std::hash::{Hash, Hasher};
+use unordered_hash::UnorderedHasher;
+
+/// A PerArchetype value which can be compared without knowing it's type.
+pub trait ArchetypeValue: Downcast + Storable {
+    fn eq_value(&self, other: &dyn ArchetypeValue) -> bool;
+}
+
+impl_downcast!(ArchetypeValue);
+
+impl<T: Eq + Storable> ArchetypeValue for T {
+    fn eq_value(&self, other: &dyn ArchetypeValue) -> bool {
+        other.downcast_ref::<T>().is_some_and(|other| self == other)
+    }
+}
+
+/// Collects the components which an archetype must have in order to store an entity.
+pub struct ArchetypeRequirements {
+    hasher: UnorderedHasher,
+    components: Vec<TypeId>,
+    values: Vec<(TypeId, Shared<dyn ArchetypeValue>)>,
+    // Sparse components do not affect the archetype, but are kept to find duplicates.
+    sparse: Vec<TypeId>,
+    duplicate: Option<&'static str>,
+}
+
+impl ArchetypeRequirements {
+    pub fn new() -> Self {
+        Self {
+            hasher: UnorderedHasher::new(),
+            components: Vec::new(),
+            values: Vec::new(),
+            sparse: Vec::new(),
+            duplicate: None,
+        }
+    }
+
+    /// Requires a component which has a value per entity.
+    pub fn add_component<T: 'static>(&mut self) {
+        let type_id = TypeId::of::<T>();
+        self.hasher.add(&type_id);
+        let index = match self.components.binary_search(&type_id) {
+            Ok(index) => {
+                self.duplicate.get_or_insert(type_name::<T>());
+                index
+            }
+            Err(index) => index,
+        };
+        self.components.insert(index, type_id);
+    }
+
+    /// Records a component which some entities of the archetype may not have. This does not change
+    /// the archetype, but the component is still checked for duplicates.
+    pub fn add_sparse<T: 'static>(&mut self) {
+        let type_id = TypeId::of::<T>();
+        match self.sparse.binary_search(&type_id) {
+            Ok(_) => {
+                self.duplicate.get_or_insert(type_name::<T>());
+            }
+            Err(index) => self.sparse.insert(index, type_id),
+        }
+    }
+
+    /// The first component which was required more than once, if any.
+    pub fn duplicate(&self) -> Option<&'static str> {
+        self.duplicate
+    }
+
+    /// Requires a component which has the same value for every entity in the archetype.
+    pub fn add_value<T: Eq + Hash + Clone + Storable>(&mut self, value: &T) {
+        self.add_component::<T>();
+        let type_id = TypeId::of::<T>();
+        // Hashed together, so that swapping values between components changes the hash.
+        self.hasher.add(&(type_id, value));
+        let index = self
+            .values
+            .binary_search_by(|(t, _)| t.cmp(&type_id))
+            .unwrap_or_else(|i| i);
+        self.values.insert(index, (type_id, Shared::new(value.clone())));
+    }
+
+    pub fn finish(self) -> ArchetypeKey {
+        ArchetypeKey {
+            hash: self.hasher.finish(),
+            components: self.components.into(),
+            values: self.values.into(),
+        }
+    }
+}
+
+/// Identifies an archetype by the exact set of it's components and the values of it's PerArchetype
+/// components. The hash is only used as a fast path, so collisions can not mix archetypes.
+#[derive(Clone)]
+pub struct ArchetypeKey {
+    hash: u64,
+    components: Shared<[TypeId]>,
+    values: Shared<[(TypeId, Shared<dyn ArchetypeValue>)]>,
+}
+
+impl ArchetypeKey {
+    /// The PerArchetype value of T, if the archetype has one.
+    pub fn value<T: ArchetypeValue>(&self) -> Option<&T> {
+        let type_id = TypeId::of::<T>();
+        let index = self.values.binary_search_by(|(t, _)| t.cmp(&type_id)).ok()?;
+        self.values[index].1.downcast_ref::<T>()
+    }
+}
+
+impl PartialEq for ArchetypeKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.hash == other.hash
+            && self.components == other.components
+            && self.values.len() == other.values.len()
+            && self
+                .values
+                .iter()
+                .zip(other.values.iter())
+                .all(|((a_type, a), (b_type, b))| a_type == b_type && a.eq_value(b.as_ref()))
+    }
+}
+
+impl Eq for ArchetypeKey {}
+
+impl Hash for ArchetypeKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.hash)
+    }
+}
@@ -0,0 +1,349 @@
+use crate::*;
+use std::any::type_name;
+
+/// The storages which are read and written by a process or query.
+#[derive(Clone, Debug, Default)]
+pub struct Access {
+    reads: Vec<StorageType>,
+    writes: Vec<StorageType>,
+    // Updates may change anything, including which archetypes exist.
+    exclusive: bool,
+}
+
+impl Access {
+    pub fn of<R: ReadableStorage, W: WritableStorage>() -> Self {
+        let mut access = Self::reads::<R>();
+        W::add_write_types(&mut access.writes);
+        access.writes.sort();
+        access
+    }
+
+    pub fn reads<R: ReadableStorage>() -> Self {
+        let mut reads = Vec::new();
+        R::add_read_types(&mut reads);
+        reads.sort();
+        reads.dedup();
+        Self {
+            reads,
+            writes: Vec::new(),
+            exclusive: false,
+        }
+    }
+
+    pub fn exclusive() -> Self {
+        Self {
+            reads: Vec::new(),
+            writes: Vec::new(),
+            exclusive: true,
+        }
+    }
+
+    /// Fails if the same storage is written more than once, or is both read and written.
+    pub fn check(&self) -> Result<(), Error> {
+        if let Some(pair) = self.writes.windows(2).find(|pair| pair[0] == pair[1]) {
+            return Err(Error::WrittenTwice { component: pair[0].name });
+        }
+        if let Some(read) = self.reads.iter().find(|read| self.writes.binary_search(read).is_ok()) {
+            return Err(Error::ReadAndWritten { component: read.name });
+        }
+        Ok(())
+    }
+
+    /// Whether something written by self is read by other.
+    fn feeds(&self, other: &Access) -> bool {
+        self.writes.iter().any(|write| other.reads.binary_search(write).is_ok())
+    }
+
+    fn writes_same(&self, other: &Access) -> bool {
+        self.writes.iter().any(|write| other.writes.binary_search(write).is_ok())
+    }
+
+    /// Reading a derived component may run it's producer, so anything which reads it also reads
+    /// the reads of the producer.
+    pub(crate) fn add_derived_reads(&mut self, producer: &Access) {
+        if producer.feeds(self) {
+            self.reads.extend(producer.reads.iter().copied());
+            self.reads.sort();
+            self.reads.dedup();
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ScheduleError {
+    /// The system reads a component that it writes, or writes a component twice.
+    Conflict(&'static str),
+    /// Each of the systems writes something which is read by the next, and the last by the first.
+    Cycle(Vec<&'static str>),
+}
+
+trait System: Storable {
+    fn name(&self) -> &'static str;
+    fn access(&self) -> Access;
+    /// Does everything which needs &mut World, returning the version to write.
+    fn prepare(&mut self, world: &mut World) -> Version;
+    /// Systems of the same stage may run this at the same time. Commands are applied after the stage.
+    fn run_shared(&mut self, world: &World, version: Version);
+}
+
+struct ProcessSystem<P>(P);
+
+impl<P: Process + Storable> System for ProcessSystem<P> {
+    fn name(&self) -> &'static str {
+        type_name::<P>()
+    }
+    fn access(&self) -> Access {
+        Access::of::<P::Reads, P::Writes>()
+    }
+    fn prepare(&mut self, world: &mut World) -> Version {
+        world.prepare_process(&self.0)
+    }
+    fn run_shared(&mut self, world: &World, version: Version) {
+        world.run_process(&self.0, version);
+    }
+}
+
+struct ProcessSimpleSystem<P>(P);
+
+impl<P: ProcessSimple + Storable> System for ProcessSimpleSystem<P> {
+    fn name(&self) -> &'static str {
+        type_name::<P>()
+    }
+    fn access(&self) -> Access {
+        Access::of::<P::Reads, P::Writes>()
+    }
+    fn prepare(&mut self, world: &mut World) -> Version {
+        world.prepare_process_simple::<P>()
+    }
+    fn run_shared(&mut self, world: &World, version: Version) {
+        world.run_process_simple(&self.0, version);
+    }
+}
+
+struct UpdateSystem<U>(U);
+
+// Updates are exclusive, so they are always alone in their stage and run entirely in prepare.
+impl<U: Update + Storable> System for UpdateSystem<U> {
+    fn name(&self) -> &'static str {
+        type_name::<U>()
+    }
+    fn access(&self) -> Access {
+        Access::exclusive()
+    }
+    fn prepare(&mut self, world: &mut World) -> Version {
+        world.execute_update(&self.0);
+        world.version()
+    }
+    fn run_shared(&mut self, _world: &World, _version: Version) {}
+}
+
+struct QuerySystem<Q, F>(Q, F);
+
+impl<Q: Query + Storable, F: FnMut(Q::Output) + Storable> System for QuerySystem<Q, F> {
+    fn name(&self) -> &'static str {
+        type_name::<Q>()
+    }
+    fn access(&self) -> Access {
+        Access::reads::<Q::Reads>()
+    }
+    fn prepare(&mut self, world: &mut World) -> Version {
+        world.update_all_derived();
+        world.version()
+    }
+    fn run_shared(&mut self, world: &World, _version: Version) {
+        let output = world.run_query(&self.0);
+        (self.1)(output);
+    }
+}
+
+/// Collects processes, updates and queries to be ordered by the components they read and write.
+#[derive(Default)]
+pub struct ScheduleBuilder {
+    systems: Vec<Box<dyn System>>,
+}
+
+impl ScheduleBuilder {
+    pub fn new() -> Self {
+        Self { systems: Vec::new() }
+    }
+
+    pub fn add_process<P: Process + Storable>(&mut self, process: P) -> &mut Self {
+        self.systems.push(Box::new(ProcessSystem(process)));
+        self
+    }
+
+    pub fn add_process_simple<P: ProcessSimple + Storable>(&mut self, process: P) -> &mut Self {
+        self.systems.push(Box::new(ProcessSimpleSystem(process)));
+        self
+    }
+
+    /// Updates run after everything added before them, and before everything added after them.
+    pub fn add_update<U: Update + Storable>(&mut self, update: U) -> &mut Self {
+        self.systems.push(Box::new(UpdateSystem(update)));
+        self
+    }
+
+    /// The output of each run of the query is passed to on_output.
+    pub fn add_query<Q: Query + Storable, F: FnMut(Q::Output) + Storable>(&mut self, query: Q, on_output: F) -> &mut Self {
+        self.systems.push(Box::new(QuerySystem(query, on_output)));
+        self
+    }
+
+    /// Orders the systems so that a system which writes a component runs before those that read it.
+    /// Systems which write the same component, or which are not otherwise ordered, run in the order
+    /// they were added. A system which reads a component derived by the world also reads the inputs
+    /// of the component, see World::register_derived.
+    pub fn build(self, world: &World) -> Result<Schedule, ScheduleError> {
+        let access: Vec<Access> = self.systems.iter().map(|s| s.access()).collect();
+        for (system, access) in self.systems.iter().zip(access.iter()) {
+            if access.check().is_err() {
+                return Err(ScheduleError::Conflict(system.name()));
+            }
+        }
+        let access: Vec<Access> = access.into_iter().map(|a| world.with_derived_reads(a)).collect();
+
+        // dependencies[j] are the systems which must run before j
+        let count = self.systems.len();
+        let mut dependencies = vec![Vec::new(); count];
+        for j in 0..count {
+            for i in 0..j {
+                let (a, b) = (&access[i], &access[j]);
+                if a.exclusive || b.exclusive || a.writes_same(b) || a.feeds(b) {
+                    dependencies[j].push(i);
+                }
+                if b.feeds(a) {
+                    dependencies[i].push(j);
+                }
+            }
+        }
+        self.into_stages(dependencies)
+    }
+
+    /// Orders the systems so that those which conflict run in the order they were added, and those
+    /// which do not may share a stage. This can not fail, but the systems are not checked for conflicts.
+    pub(crate) fn build_in_order(self, world: &World) -> Schedule {
+        let access: Vec<Access> = self.systems.iter().map(|s| world.with_derived_reads(s.access())).collect();
+        let count = self.systems.len();
+        let mut dependencies = vec![Vec::new(); count];
+        for j in 0..count {
+            for i in 0..j {
+                let (a, b) = (&access[i], &access[j]);
+                if a.exclusive || b.exclusive || a.writes_same(b) || a.feeds(b) || b.feeds(a) {
+                    dependencies[j].push(i);
+                }
+            }
+        }
+        self.into_stages(dependencies).unwrap()
+    }
+
+    fn into_stages(self, dependencies: Vec<Vec<usize>>) -> Result<Schedule, ScheduleError> {
+        // Each system goes in the stage after the last of it's dependencies.
+        let count = self.systems.len();
+        let mut stage_of: Vec<Option<usize>> = vec![None; count];
+        let mut remaining = count;
+        while remaining != 0 {
+            let mut progress = false;
+            for j in 0..count {
+                if stage_of[j].is_some() {
+                    continue;
+                }
+                let mut stage = Some(0);
+                for &i in dependencies[j].iter() {
+                    stage = match (stage, stage_of[i]) {
+                        (Some(stage), Some(before)) => Some(stage.max(before + 1)),
+                        _ => None,
+                    };
+                }
+                if let Some(stage) = stage {
+                    stage_of[j] = Some(stage);
+                    remaining -= 1;
+                    progress = true;
+                }
+            }
+            if !progress {
+                return Err(ScheduleError::Cycle(find_cycle(&dependencies, &stage_of, &self.systems)));
+            }
+        }
+
+        let mut stages = Vec::<Vec<usize>>::new();
+        for (system, stage) in stage_of.into_iter().enumerate() {
+            let stage = stage.unwrap();
+            if stages.len() <= stage {
+                stages.resize(stage + 1, Vec::new());
+            }
+            stages[stage].push(system);
+        }
+        Ok(Schedule {
+            systems: self.systems,
+            stages,
+        })
+    }
+}
+
+// Follows unplaced dependencies from an unplaced system until one repeats.
+fn find_cycle(dependencies: &[Vec<usize>], stage_of: &[Option<usize>], systems: &[Box<dyn System>]) -> Vec<&'static str> {
+    let mut path = Vec::new();
+    let mut current = stage_of.iter().position(Option::is_none).unwrap();
+    while !path.contains(&current) {
+        path.push(current);
+        current = *dependencies[current]
+            .iter()
+            .find(|&&i| stage_of[i].is_none())
+            .unwrap();
+    }
+    let start = path.iter().position(|&i| i == current).unwrap();
+    let mut cycle = path.split_off(start);
+    // The path follows dependencies backwards. Start from the system which was added first.
+    cycle.reverse();
+    let first = cycle.iter().enumerate().min_by_key(|(_, &i)| i).unwrap().0;
+    cycle.rotate_left(first);
+    cycle.into_iter().map(|i| systems[i].name()).collect()
+}
+
+/// Systems in stages, where the systems of a stage do not depend on each other.
+pub struct Schedule {
+    systems: Vec<Box<dyn System>>,
+    stages: Vec<Vec<usize>>,
+}
+
+impl Schedule {
+    /// Runs each stage in order, and the systems of each stage in the order they were added.
+    /// With the parallel feature, the systems of a stage run at the same time on the rayon thread pool.
+    /// Either way, commands are applied after each stage, so the systems of a stage do not see each
+    /// other's commands.
+    pub fn run(&mut self, world: &mut World) {
+        for stage in self.stages.iter() {
+            let mut systems: Vec<(&mut Box<dyn System>, Version)> = self
+                .systems
+                .iter_mut()
+                .enumerate()
+                .filter(|(index, _)| stage.contains(index))
+                .map(|(_, system)| {
+                    let version = system.prepare(world);
+                    (system, version)
+                })
+                .collect();
+            let shared: &World = world;
+            #[cfg(not(feature = "parallel"))]
+            systems
+                .iter_mut()
+                .for_each(|(system, version)| system.run_shared(shared, *version));
+            #[cfg(feature = "parallel")]
+            {
+                use rayon::prelude::*;
+                systems
+                    .par_iter_mut()
+                    .for_each(|(system, version)| system.run_shared(shared, *version));
+            }
+            world.apply_commands();
+        }
+    }
+
+    /// The names of the systems in each stage.
+    pub fn stages(&self) -> Vec<Vec<&'static str>> {
+        self.stages
+            .iter()
+            .map(|stage| stage.iter().map(|&s| self.systems[s].name()).collect())
+            .collect()
+    }
+}
@@ -1,10 +1,22 @@
 use crate::*;
 use std::any::TypeId;
 use std::collections::HashMap;
-use std::rc::Rc;
+use crate::sync::Shared;
+
+#[cfg(not(feature = "parallel"))]
+#[inline(always)]
+fn downcast<T: AnyStorage>(storage: Shared<dyn AnyStorage>) -> Result<Shared<T>, Shared<dyn AnyStorage>> {
+    storage.downcast_rc()
+}
+
+#[cfg(feature = "parallel")]
+#[inline(always)]
+fn downcast<T: AnyStorage>(storage: Shared<dyn AnyStorage>) -> Result<Shared<T>, Shared<dyn AnyStorage>> {
+    storage.downcast_arc()
+}
 
 pub struct Components {
-    pub any: HashMap<TypeId, Rc<dyn AnyStorage>>,
+    pub any: HashMap<TypeId, Shared<dyn AnyStorage>>,
 }
 
 impl Components {
@@ -14,9 +26,9 @@ impl Components {
         }
     }
 
-    pub fn get_storage<T: AnyStorage>(&self) -> Option<Rc<T>> {
+    pub fn get_storage<T: AnyStorage>(&self) -> Option<Shared<T>> {
         match self.any.get(&TypeId::of::<T>()) {
-            Some(storage) => match storage.clone().downcast_rc() {
+            Some(storage) => match downcast(storage.clone()) {
                 Ok(r) => Some(r),
                 Err(_) => unreachable!(),
             },
@@ -24,9 +36,9 @@ impl Components {
         }
     }
 
-    pub fn get_storage_mut<T: AnyStorage>(&self) -> Option<Rc<T>> {
+    pub fn get_storage_mut<T: AnyStorage>(&self) -> Option<Shared<T>> {
         match self.any.get(&TypeId::of::<T>()) {
-            Some(storage) => match storage.clone().downcast_rc() {
+            Some(storage) => match downcast(storage.clone()) {
                 Ok(r) => Some(r),
                 Err(_) => unreachable!(),
             },
@@ -34,13 +46,25 @@ impl Components {
         }
     }
 
+    #[inline]
+    pub fn contains<T: AnyStorage>(&self) -> bool {
+        self.any.contains_key(&TypeId::of::<T>())
+    }
+
     pub fn add<T: AnyStorage>(&mut self, storage: T) {
         let id = TypeId::of::<T>();
         assert!(
             self.any.get(&id).is_none(),
             "Added component twice to the same archetype"
         );
-        self.any.insert(id, Rc::new(storage));
+        self.any.insert(id, Shared::new(storage));
+    }
+
+    pub fn remove<T: AnyStorage>(&mut self) -> Option<Shared<T>> {
+        match downcast(self.any.remove(&TypeId::of::<T>())?) {
+            Ok(r) => Some(r),
+            Err(_) => unreachable!(),
+        }
     }
 
 	pub fn remove_entity(&mut self, index: usize, top: usize) {
@@ -1,7 +1,6 @@
 use crate::*;
-use extend_lifetime::extend_lifetime;
-use std::cell::{Ref, RefCell};
-use std::rc::Rc;
+use crate::sync::{Ref, RefCell, RefMut};
+use crate::sync::Shared;
 
 pub struct Global<T> {
     cell: RefCell<BorrowedGlobal<T>>,
@@ -9,6 +8,7 @@ pub struct Global<T> {
 
 pub struct BorrowedGlobal<T> {
     version: Version,
+    current_as_of: Version,
     value: T,
 }
 
@@ -17,21 +17,25 @@ impl<T> BorrowedGlobal<T> {
         Self {
             value,
             version: Version(0),
+            current_as_of: Version(0),
         }
     }
 }
 
-impl<T: 'static> BorrowedStorage for BorrowedGlobal<T> {
-    type Item = &'static T;
-    type Batch = &'static T;
+impl<T: Storable> BorrowedStorage for BorrowedGlobal<T> {
+    type Item<'a> = &'a T;
+    type Batch<'a> = &'a T;
+    type EntityItem<'a> = &'a T;
     #[inline(always)]
-    fn read(&self, _index: usize) -> Option<Self::Item> {
-        // See also 0a427633-4da0-4729-bae6-45d77542261c
-        unsafe { extend_lifetime(Some(&self.value)) }
+    fn read(&self, _index: usize) -> Option<&T> {
+        Some(&self.value)
     }
-    fn read_batch(&self) -> Self::Batch {
-        // See also 0a427633-4da0-4729-bae6-45d77542261c
-        unsafe { extend_lifetime(&self.value) }
+    #[inline(always)]
+    fn read_entity(&self, _index: usize) -> &T {
+        &self.value
+    }
+    fn read_batch(&self) -> &T {
+        &self.value
     }
     #[inline(always)]
     fn version(&self) -> Version {
@@ -39,31 +43,126 @@ impl<T: 'static> BorrowedStorage for BorrowedGlobal<T> {
     }
 }
 
+impl<T: Storable> BorrowedStorageMut for BorrowedGlobal<T> {
+    type ItemMut = T;
+    type BatchMut<'a> = &'a mut T;
+    #[inline(always)]
+    fn write(&mut self, _index: usize, item: T) {
+        self.value = item;
+    }
+    fn write_batch(&mut self) -> &mut T {
+        &mut self.value
+    }
+    #[inline(always)]
+    fn set_version(&mut self, version: Version) {
+        self.version = version;
+    }
+    #[inline(always)]
+    fn current_as_of(&self) -> Version {
+        self.current_as_of
+    }
+    #[inline(always)]
+    fn set_current_as_of(&mut self, version: Version) {
+        self.current_as_of = version;
+    }
+}
+
 impl<T> Global<T> {
     pub fn new(value: T) -> Self {
         let cell = RefCell::new(BorrowedGlobal::new(value));
         Self { cell }
     }
+
+    pub fn with_version(value: T, version: Version) -> Self {
+        let global = Self::new(value);
+        global.cell.borrow_mut().version = version;
+        global
+    }
+
+    pub fn get(&self) -> Ref<'_, T> {
+        Ref::map(self.cell.borrow(), |b| &b.value)
+    }
+
+    pub fn get_mut(&self) -> RefMut<'_, T> {
+        RefMut::map(self.cell.borrow_mut(), |b| &mut b.value)
+    }
+
+    pub fn into_inner(self) -> T {
+        self.cell.into_inner().value
+    }
 }
 
-impl<T: 'static> AnyStorage for Global<T> {
+impl<T: Storable> AnyStorage for Global<T> {
+	fn component_name(&self) -> &'static str {
+		std::any::type_name::<T>()
+	}
+
 	fn remove_entity(&self, _index: usize, _top: usize) {
 
 	}
+
+	fn move_entity(&self, _index: usize, _top: usize, _destination: &mut Archetype, _destination_index: usize) {
+
+	}
+
+	fn add_requirements(&self, _requirements: &mut ArchetypeRequirements) {
+
+	}
+
+	fn contains(&self, _index: usize) -> bool {
+		false
+	}
+
+	fn set_version(&self, version: Version) {
+		self.cell.borrow_mut().version = version;
+	}
 }
 
-impl<T: 'static> ReadableStorage for Global<T> {
-    type Read = Rc<Self>;
+impl<T: Storable> ReadableStorage for Global<T> {
+    type Read = Shared<Self>;
     #[inline(always)]
     fn get(world_storage: &Components, _archetype_storage: &Components) -> Option<Self::Read> {
         world_storage.get_storage::<Self>()
     }
+    fn add_read_types(types: &mut Vec<StorageType>) {
+        types.push(StorageType::of::<Self>());
+    }
+}
+
+impl<T: Storable + Clone> ReadableStorageOwned for Global<T> {
+    type Owned = T;
+    fn read_owned(read: &Self::Read, index: usize) -> Option<T> {
+        read.borrow().read(index).cloned()
+    }
+}
+
+// A process which writes a Global borrows it mutably once per archetype, releasing
+// the borrow before moving on to the next archetype.
+impl<T: Storable> WritableStorage for Global<T> {
+    type ReadMut = Shared<Self>;
+    #[inline(always)]
+    fn get_mut(world_storage: &Components, _archetype: &Archetype) -> Option<Self::ReadMut> {
+        world_storage.get_storage_mut::<Self>()
+    }
+    fn add_write_types(types: &mut Vec<StorageType>) {
+        types.push(StorageType::of::<Self>());
+    }
+}
+
+impl<T: Storable> RefLikeMut for Global<T> {
+    type ItemMut = T;
+    type BorrowedMut<'a> = RefMut<'a, BorrowedGlobal<T>>;
+    fn borrow_mut(&self) -> Self::BorrowedMut<'_> {
+        self.cell.borrow_mut()
+    }
+    fn current_as_of(&self) -> Version {
+        self.cell.borrow().current_as_of()
+    }
 }
 
-impl<T: 'static> RefLike for Global<T> {
-    type Borrowed = Ref<'static, BorrowedGlobal<T>>;
-    fn borrow(&self) -> Self::Borrowed {
-        // See also 0a427633-4da0-4729-bae6-45d77542261c
-        unsafe { extend_lifetime(self.cell.borrow()) }
+impl<T: Storable> RefLike for Global<T> {
+    type Borrowed<'a> = Ref<'a, BorrowedGlobal<T>>;
+    fn borrow(&self) -> Self::Borrowed<'_> {
+        self.cell.borrow()
     }
 }
@@ -1,132 +1,248 @@
 use crate::*;
-use downcast_rs::Downcast;
-use extend_lifetime::extend_lifetime;
-use std::cell::{Ref, RefCell, RefMut};
+use std::any::TypeId;
+use crate::sync::{Ref, RefCell, RefMut, SharedDowncast};
 use std::ops::{Deref, DerefMut};
-use std::rc::Rc;
-use unordered_hash::UnorderedHasher;
+use crate::sync::Shared;
 
-pub trait AnyStorage: Downcast {
+pub trait AnyStorage: SharedDowncast {
 	fn remove_entity(&self, index: usize, top: usize);
+	/// Like remove_entity, but the value at index is written to destination_index in the destination archetype.
+	/// The storage is added to the destination if it does not have it yet.
+	fn move_entity(&self, index: usize, top: usize, destination: &mut Archetype, destination_index: usize);
+	/// Adds the same requirements that the EntityWriter of the stored component would.
+	fn add_requirements(&self, requirements: &mut ArchetypeRequirements);
+	/// Whether the storage has a value for the entity at index.
+	fn contains(&self, index: usize) -> bool;
+	fn set_version(&self, version: Version);
+	/// The type name of the stored component, for errors.
+	fn component_name(&self) -> &'static str;
 }
 
+#[cfg(not(feature = "parallel"))]
 impl_downcast!(AnyStorage);
+#[cfg(feature = "parallel")]
+impl_downcast!(sync AnyStorage);
 
-#[derive(Copy, Clone, Eq, Ord, PartialEq, PartialOrd, Hash)]
+#[derive(Copy, Clone, Eq, Ord, PartialEq, PartialOrd, Hash, Debug)]
 pub struct Version(pub u64);
 
-pub trait RefLike {
+/// Identifies a storage by it's TypeId, with the name of the type for errors.
+#[derive(Copy, Clone, Debug)]
+pub struct StorageType {
+    pub id: TypeId,
+    pub name: &'static str,
+}
+
+impl StorageType {
+    pub fn of<T: 'static>() -> Self {
+        Self {
+            id: TypeId::of::<T>(),
+            name: std::any::type_name::<T>(),
+        }
+    }
+}
+
+impl PartialEq for StorageType {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for StorageType {}
+
+impl PartialOrd for StorageType {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for StorageType {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.id.cmp(&other.id)
+    }
+}
+
+pub trait RefLike: Storable {
     // TODO: There's no reason for this trait's associated type to require BorrowedStorage,
     // but in ReadableStorage we need to specify this (and that's the only place this is used now)
     // See also c1d1ffbe-1226-41ed-9190-6e8c32ccdced
-    type Borrowed: BorrowedStorage;
-    fn borrow(&self) -> Self::Borrowed;
+    type Borrowed<'a>: BorrowedStorage;
+    fn borrow(&self) -> Self::Borrowed<'_>;
 }
 
-impl<T: ReadableStorage> ReadableStorage for Rc<T> {
+impl<T: ReadableStorage> ReadableStorage for Shared<T> {
     type Read = T::Read;
     #[inline(always)]
     fn get(world_storage: &Components, archetype_storage: &Components) -> Option<Self::Read> {
         T::get(world_storage, archetype_storage)
     }
+    #[inline(always)]
+    fn add_read_types(types: &mut Vec<StorageType>) {
+        T::add_read_types(types)
+    }
+    #[inline(always)]
+    fn changed_since(read: &Self::Read, since: Version) -> bool {
+        T::changed_since(read, since)
+    }
 }
 
-impl<T: RefLike> RefLike for Rc<T> {
-    type Borrowed = T::Borrowed;
-    fn borrow(&self) -> Self::Borrowed {
+impl<T: RefLike> RefLike for Shared<T> {
+    type Borrowed<'a> = T::Borrowed<'a>;
+    fn borrow(&self) -> Self::Borrowed<'_> {
         self.deref().borrow()
     }
 }
 
-impl<T: RefLikeMut> RefLikeMut for Rc<T> {
-    type BorrowedMut = T::BorrowedMut;
-    fn borrow_mut(&self) -> Self::BorrowedMut {
+impl<T: RefLikeMut> RefLikeMut for Shared<T> {
+    type ItemMut = T::ItemMut;
+    type BorrowedMut<'a> = T::BorrowedMut<'a>;
+    fn borrow_mut(&self) -> Self::BorrowedMut<'_> {
         Deref::deref(self).borrow_mut()
     }
+    fn current_as_of(&self) -> Version {
+        Deref::deref(self).current_as_of()
+    }
 }
 
-pub trait RefLikeMut {
+pub trait RefLikeMut: Storable {
     // See also c1d1ffbe-1226-41ed-9190-6e8c32ccdced
-    type BorrowedMut: BorrowedStorageMut;
-    fn borrow_mut(&self) -> Self::BorrowedMut;
+    /// The item written for each entity, see BorrowedStorageMut::write
+    type ItemMut;
+    type BorrowedMut<'a>: BorrowedStorageMut<ItemMut = Self::ItemMut>;
+    fn borrow_mut(&self) -> Self::BorrowedMut<'_>;
+    /// See BorrowedStorageMut::current_as_of. Only borrows immutably, so that it can be
+    /// checked while the storage is being read elsewhere.
+    fn current_as_of(&self) -> Version;
 }
 
-impl<T: BorrowedStorage + 'static> RefLike for RefCell<T> {
-    type Borrowed = Ref<'static, T>;
+impl<T: BorrowedStorage + Storable> RefLike for RefCell<T> {
+    type Borrowed<'a> = Ref<'a, T>;
     #[inline(always)]
-    fn borrow(&self) -> Self::Borrowed {
-        // We can't get a borrow with references from self without GAT, so we
-        // very unsafely lie to the compiler about the nature of our references. :/
-        // See http://lukaskalbertodt.github.io/2018/08/03/solving-the-generalized-streaming-iterator-problem-without-gats.html#summary
-        // See http://smallcultfollowing.com/babysteps/blog/2016/11/02/associated-type-constructors-part-1-basic-concepts-and-introduction/
-        // See the other parts of the previous blog post
-        // See also 0a427633-4da0-4729-bae6-45d77542261c
-        unsafe { extend_lifetime(self.borrow()) }
+    fn borrow(&self) -> Self::Borrowed<'_> {
+        self.borrow()
     }
 }
 
-impl<B: BorrowedStorage> BorrowedStorage for Ref<'static, B> {
-    type Item = B::Item;
-    type Batch = B::Batch;
+impl<B: BorrowedStorage> BorrowedStorage for Ref<'_, B> {
+    type Item<'a> = B::Item<'a> where Self: 'a;
+    type Batch<'a> = B::Batch<'a> where Self: 'a;
+    type EntityItem<'a> = B::EntityItem<'a> where Self: 'a;
     #[inline(always)]
     fn version(&self) -> Version {
         self.deref().version()
     }
     #[inline(always)]
-    fn read(&self, index: usize) -> Option<Self::Item> {
+    fn read(&self, index: usize) -> Option<Self::Item<'_>> {
         self.deref().read(index)
     }
     #[inline(always)]
-    fn read_batch(&self) -> Self::Batch {
+    fn read_entity(&self, index: usize) -> Self::EntityItem<'_> {
+        self.deref().read_entity(index)
+    }
+    #[inline(always)]
+    fn read_batch(&self) -> Self::Batch<'_> {
         self.deref().read_batch()
     }
 }
 
-impl<T: BorrowedStorageMut + 'static> RefLikeMut for RefCell<T> {
-    type BorrowedMut = RefMut<'static, T>;
-    fn borrow_mut(&self) -> Self::BorrowedMut {
-        // See also 0a427633-4da0-4729-bae6-45d77542261c
-        unsafe { extend_lifetime(self.borrow_mut()) }
+impl<T: BorrowedStorageMut + Storable> RefLikeMut for RefCell<T> {
+    type ItemMut = T::ItemMut;
+    type BorrowedMut<'a> = RefMut<'a, T>;
+    fn borrow_mut(&self) -> Self::BorrowedMut<'_> {
+        self.borrow_mut()
+    }
+    fn current_as_of(&self) -> Version {
+        self.borrow().current_as_of()
     }
 }
 
-impl<T: BorrowedStorageMut + 'static> BorrowedStorageMut for RefMut<'static, T> {
+impl<T: BorrowedStorageMut> BorrowedStorageMut for RefMut<'_, T> {
     type ItemMut = T::ItemMut;
-    type BatchMut = T::BatchMut;
+    type BatchMut<'a> = T::BatchMut<'a> where Self: 'a;
     #[inline(always)]
     fn write(&mut self, index: usize, item: Self::ItemMut) {
         self.deref_mut().write(index, item)
     }
     #[inline(always)]
-    fn write_batch(&mut self) -> Self::BatchMut {
+    fn write_batch(&mut self) -> Self::BatchMut<'_> {
         self.deref_mut().write_batch()
     }
+    #[inline(always)]
+    fn set_version(&mut self, version: Version) {
+        self.deref_mut().set_version(version)
+    }
+    #[inline(always)]
+    fn current_as_of(&self) -> Version {
+        self.deref().current_as_of()
+    }
+    #[inline(always)]
+    fn set_current_as_of(&mut self, version: Version) {
+        self.deref_mut().set_current_as_of(version)
+    }
 }
 
+/// A borrowed storage. Items and batches borrow from it, so they can not outlive the borrow.
 pub trait BorrowedStorage {
-    type Item;
-    type Batch;
+    type Item<'a>
+    where
+        Self: 'a;
+    type Batch<'a>
+    where
+        Self: 'a;
+    /// The item of each entity for QueryData::entities. Unlike read this is given for every entity,
+    /// eg: Option<&T> for Sparse.
+    type EntityItem<'a>
+    where
+        Self: 'a;
     fn version(&self) -> Version;
-    fn read(&self, index: usize) -> Option<Self::Item>;
-    fn read_batch(&self) -> Self::Batch;
+    fn read(&self, index: usize) -> Option<Self::Item<'_>>;
+    fn read_entity(&self, index: usize) -> Self::EntityItem<'_>;
+    fn read_batch(&self) -> Self::Batch<'_>;
 }
 
 pub trait BorrowedStorageMut {
     type ItemMut;
-    type BatchMut;
+    type BatchMut<'a>
+    where
+        Self: 'a;
     fn write(&mut self, index: usize, item: Self::ItemMut);
-    fn write_batch(&mut self) -> Self::BatchMut;
+    fn write_batch(&mut self) -> Self::BatchMut<'_>;
+    fn set_version(&mut self, version: Version);
+    /// The version of the inputs the values were last derived from. See also World::register_derived
+    fn current_as_of(&self) -> Version;
+    fn set_current_as_of(&mut self, version: Version);
 }
 
 pub trait ReadableStorage {
     // TODO: Associated type bound, Borrow=BorrowedStorage. See also c1d1ffbe-1226-41ed-9190-6e8c32ccdced
     type Read: RefLike;
     fn get(world_storage: &Components, archetype_storage: &Components) -> Option<Self::Read>;
+    /// Adds the TypeId of each storage which is read.
+    fn add_read_types(types: &mut Vec<StorageType>);
+    /// Whether the storage has changed since the version. Only Changed<T> is ever considered
+    /// unchanged, allowing queries and processes to skip archetypes they have already seen.
+    #[inline(always)]
+    fn changed_since(_read: &Self::Read, _since: Version) -> bool {
+        true
+    }
+}
+
+/// Storages from which the items of an entity can be cloned, so that they outlive the borrow.
+/// See also World::read_component
+pub trait ReadableStorageOwned: ReadableStorage {
+    type Owned;
+    fn read_owned(read: &Self::Read, index: usize) -> Option<Self::Owned>;
 }
 
 pub trait WritableStorage: ReadableStorage {
     type ReadMut: RefLikeMut;
-    fn get_mut(archetype: &Archetype) -> Option<Self::ReadMut>;
+    fn get_mut(world_storage: &Components, archetype: &Archetype) -> Option<Self::ReadMut>;
+    /// Adds the TypeId of each storage which is written.
+    fn add_write_types(types: &mut Vec<StorageType>);
+    /// Called before get_mut when the archetype can be modified, to add storage which can be
+    /// written without changing the archetype.
+    #[inline(always)]
+    fn prepare(_archetype: &mut Archetype) {}
 }
 
 pub trait EntityStorage {
@@ -156,7 +272,7 @@ impl<
 pub trait EntityWriterFromComponentStorage {
     type Component;
     fn write(component: Self::Component, archetype: &mut Archetype, index: usize);
-	fn add_archetype_requirements(component: &Self::Component, hasher: &mut UnorderedHasher);
+	fn add_archetype_requirements(component: &Self::Component, requirements: &mut ArchetypeRequirements);
 }
 
 impl<
@@ -170,11 +286,43 @@ impl<
     }
 
 	#[inline]
-	fn add_archetype_requirements(&self, hasher: &mut UnorderedHasher) {
-		S::add_archetype_requirements(&self, hasher)
+	fn add_archetype_requirements(&self, requirements: &mut ArchetypeRequirements) {
+		S::add_archetype_requirements(self, requirements)
 	}
 }
 
+pub trait EntityReaderFromComponentStorage {
+    type Component;
+    /// Removes the value at index, moving the value at top into it's place as AnyStorage::remove_entity does.
+    /// Returns None if the archetype does not have a value for the entity.
+    fn take(archetype: &Archetype, index: usize, top: usize) -> Option<Self::Component>;
+}
+
+impl<
+        S: EntityReaderFromComponentStorage<Component = T> + ReadableStorage + AnyStorage,
+        T: Component<Storage = S>,
+    > EntityReader for T
+{
+    #[inline]
+    fn add_storage_types(types: &mut Vec<StorageType>) {
+        types.push(StorageType {
+            id: TypeId::of::<S>(),
+            name: std::any::type_name::<T>(),
+        })
+    }
+
+    #[inline]
+    fn take(archetype: &Archetype, index: usize, top: usize) -> Option<Self> {
+        S::take(archetype, index, top)
+    }
+}
+
+/// Storages which can lend the component of a single entity. See also World::component
+pub trait BorrowFromComponentStorage {
+    type Component;
+    fn borrow_component(&self, index: usize) -> Option<Ref<'_, Self::Component>>;
+}
+
 mod per_entity;
 pub use per_entity::*;
 mod per_archetype;
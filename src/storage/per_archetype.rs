@@ -1,9 +1,7 @@
 use crate::*;
-use extend_lifetime::extend_lifetime;
-use std::cell::{Ref, RefCell};
-use std::rc::Rc;
+use crate::sync::{Ref, RefCell};
+use crate::sync::Shared;
 use std::hash::Hash;
-use unordered_hash::UnorderedHasher;
 
 pub struct PerArchetype<T> {
     cell: RefCell<BorrowedPerArchetype<T>>,
@@ -23,17 +21,20 @@ impl<T> BorrowedPerArchetype<T> {
     }
 }
 
-impl<T: 'static> BorrowedStorage for BorrowedPerArchetype<T> {
-    type Item = &'static T;
-    type Batch = &'static T;
+impl<T: Storable> BorrowedStorage for BorrowedPerArchetype<T> {
+    type Item<'a> = &'a T;
+    type Batch<'a> = &'a T;
+    type EntityItem<'a> = &'a T;
     #[inline(always)]
-    fn read(&self, _index: usize) -> Option<Self::Item> {
-        // See also 0a427633-4da0-4729-bae6-45d77542261c
-        unsafe { extend_lifetime(Some(&self.value)) }
+    fn read(&self, _index: usize) -> Option<&T> {
+        Some(&self.value)
     }
-    fn read_batch(&self) -> Self::Batch {
-        // See also 0a427633-4da0-4729-bae6-45d77542261c
-        unsafe { extend_lifetime(&self.value) }
+    #[inline(always)]
+    fn read_entity(&self, _index: usize) -> &T {
+        &self.value
+    }
+    fn read_batch(&self) -> &T {
+        &self.value
     }
     #[inline(always)]
     fn version(&self) -> Version {
@@ -48,23 +49,57 @@ impl<T> PerArchetype<T> {
     }
 }
 
-impl<T: 'static> AnyStorage for PerArchetype<T> {
+impl<T: Clone + Eq + Hash + Storable> AnyStorage for PerArchetype<T> {
+	fn component_name(&self) -> &'static str {
+		std::any::type_name::<T>()
+	}
+
 	#[inline]
 	fn remove_entity(&self, _index: usize, _top: usize) { }
+
+	fn move_entity(&self, _index: usize, _top: usize, destination: &mut Archetype, _destination_index: usize) {
+		if destination.get_storage::<Self>().is_none() {
+			let value = self.cell.borrow().value.clone();
+			destination.components_mut().add(Self::new(value));
+		}
+	}
+
+	#[inline]
+	fn add_requirements(&self, requirements: &mut ArchetypeRequirements) {
+		requirements.add_value(&self.cell.borrow().value);
+	}
+
+	#[inline]
+	fn contains(&self, _index: usize) -> bool {
+		true
+	}
+
+	fn set_version(&self, version: Version) {
+		self.cell.borrow_mut().version = version;
+	}
 }
 
-impl<T: 'static> ReadableStorage for PerArchetype<T> {
-    type Read = Rc<Self>;
+impl<T: Clone + Eq + Hash + Storable> ReadableStorage for PerArchetype<T> {
+    type Read = Shared<Self>;
     fn get(_world_storage: &Components, archetype_storage: &Components) -> Option<Self::Read> {
         archetype_storage.get_storage::<Self>()
     }
+    fn add_read_types(types: &mut Vec<StorageType>) {
+        types.push(StorageType::of::<Self>());
+    }
+}
+
+impl<T: Clone + Eq + Hash + Storable> ReadableStorageOwned for PerArchetype<T> {
+    type Owned = T;
+    fn read_owned(read: &Self::Read, index: usize) -> Option<T> {
+        read.borrow().read(index).cloned()
+    }
 }
 
-impl<T: 'static> RefLike for PerArchetype<T> {
-    type Borrowed = Ref<'static, BorrowedPerArchetype<T>>;
-    fn borrow(&self) -> Self::Borrowed {
-        // See also 0a427633-4da0-4729-bae6-45d77542261c
-        unsafe { extend_lifetime(self.cell.borrow()) }
+impl<T: Clone + Eq + Hash + Storable> RefLike for PerArchetype<T> {
+    type Borrowed<'a> = Ref<'a, BorrowedPerArchetype<T>>;
+    fn borrow(&self) -> Self::Borrowed<'_> {
+        self.cell.borrow()
     }
 }
 
@@ -73,17 +108,34 @@ impl<T: Component<Storage = PerArchetype<T>>> ArchetypeInitializerFromComponentS
 {
     type Component = T;
     fn initialize(component: T, archetype: &mut Archetype) {
-        let storage = Self::new(component); // FIXME Version
+        let storage = Self::new(component);
         archetype.add_storage::<T>(storage);
     }
 }
 
-impl<T: Component<Storage = PerArchetype<T>> + Hash> EntityWriterFromComponentStorage for PerArchetype<T> {
+impl<T: Component<Storage = PerArchetype<T>> + Clone + Eq + Hash> EntityWriterFromComponentStorage for PerArchetype<T> {
     type Component = T;
     #[inline]
     fn write(_component: T, _archetype: &mut Archetype, _index: usize) {}
 	#[inline]
-	fn add_archetype_requirements(component: &Self::Component, hasher: &mut UnorderedHasher) {
-		hasher.add(component);
+	fn add_archetype_requirements(component: &Self::Component, requirements: &mut ArchetypeRequirements) {
+		requirements.add_value(component);
 	}
 }
+
+impl<T: Component<Storage = PerArchetype<T>>> BorrowFromComponentStorage for PerArchetype<T> {
+    type Component = T;
+    fn borrow_component(&self, _index: usize) -> Option<Ref<'_, T>> {
+        Some(Ref::map(self.cell.borrow(), |b| &b.value))
+    }
+}
+
+impl<T: Component<Storage = PerArchetype<T>> + Clone + Eq + Hash> EntityReaderFromComponentStorage for PerArchetype<T> {
+    type Component = T;
+    #[inline]
+    fn take(archetype: &Archetype, _index: usize, _top: usize) -> Option<T> {
+        let storage = archetype.get_storage::<Self>()?;
+        let value = storage.cell.borrow().value.clone();
+        Some(value)
+    }
+}
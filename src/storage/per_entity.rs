@@ -1,18 +1,18 @@
 use crate::*;
-use extend_lifetime::extend_lifetime;
-use std::cell::{Ref, RefCell, RefMut};
-use std::rc::Rc;
-use unordered_hash::UnorderedHasher;
-use std::any::TypeId;
+use crate::sync::{Ref, RefCell, RefMut};
+use crate::sync::Shared;
 
+// The maximum number of entities in an archetype, after which the World
+// starts another chunk of the same archetype.
 // This is quite a bit smaller than one might expect from another ECS,
 // but is smaller to balance the cost that there is a larger cardinality
 // of archetypes due to PerArchetype storage
-const CAPACITY: usize = 4096;
+pub(crate) const CAPACITY: usize = 4096;
 
 pub struct BorrowedPerEntity<T> {
     values: Vec<T>,
     version: Version,
+    current_as_of: Version,
 }
 
 impl<T> BorrowedPerEntity<T> {
@@ -20,47 +20,76 @@ impl<T> BorrowedPerEntity<T> {
         Self {
             values: Vec::with_capacity(CAPACITY),
             version: Version(0),
+            current_as_of: Version(0),
         }
     }
+
+    #[inline(always)]
+    fn insert(&mut self, index: usize, value: T) {
+        if index == self.values.len() {
+            self.values.push(value)
+        } else {
+            self.values[index] = value;
+        }
+    }
+
+    #[inline(always)]
+    fn swap_remove(&mut self, index: usize, top: usize) -> T {
+        let value = self.values.swap_remove(index);
+        debug_assert!(top == self.values.len());
+        value
+    }
 }
 
-impl<T: 'static> BorrowedStorage for BorrowedPerEntity<T> {
-    type Item = &'static T;
-    type Batch = &'static [T];
+impl<T: Storable> BorrowedStorage for BorrowedPerEntity<T> {
+    type Item<'a> = &'a T;
+    type Batch<'a> = &'a [T];
+    type EntityItem<'a> = &'a T;
     #[inline(always)]
     fn version(&self) -> Version {
         self.version
     }
     #[inline(always)]
-    fn read(&self, index: usize) -> Option<&'static T> {
-        let borrow = Some(if cfg!(debug_assertions) {
+    fn read(&self, index: usize) -> Option<&T> {
+        Some(if cfg!(debug_assertions) {
             &self.values[index]
         } else {
             unsafe { self.values.get_unchecked(index) }
-        });
-        // See also 0a427633-4da0-4729-bae6-45d77542261c
-        unsafe { extend_lifetime(borrow) }
+        })
     }
     #[inline(always)]
-    fn read_batch(&self) -> Self::Batch {
-        // See also 0a427633-4da0-4729-bae6-45d77542261c
-        unsafe { extend_lifetime(&self.values[..]) }
+    fn read_entity(&self, index: usize) -> &T {
+        self.read(index).unwrap()
+    }
+    #[inline(always)]
+    fn read_batch(&self) -> &[T] {
+        &self.values[..]
     }
 }
 
-impl<T: 'static> BorrowedStorageMut for BorrowedPerEntity<T> {
+impl<T: Storable> BorrowedStorageMut for BorrowedPerEntity<T> {
     type ItemMut = T;
-    type BatchMut = &'static mut [T];
+    type BatchMut<'a> = &'a mut [T];
     #[inline(always)]
     fn write(&mut self, index: usize, item: Self::ItemMut) {
         // TODO: Unchecked in release
         self.values[index] = item;
     }
     #[inline(always)]
-    fn write_batch(&mut self) -> Self::BatchMut {
-        let borrow = &mut self.values[..];
-        // See also 0a427633-4da0-4729-bae6-45d77542261c
-        unsafe { extend_lifetime(borrow) }
+    fn write_batch(&mut self) -> &mut [T] {
+        &mut self.values[..]
+    }
+    #[inline(always)]
+    fn set_version(&mut self, version: Version) {
+        self.version = version;
+    }
+    #[inline(always)]
+    fn current_as_of(&self) -> Version {
+        self.current_as_of
+    }
+    #[inline(always)]
+    fn set_current_as_of(&mut self, version: Version) {
+        self.current_as_of = version;
     }
 }
 
@@ -75,41 +104,80 @@ impl<T> PerEntity<T> {
     }
 }
 
-impl<T: 'static> AnyStorage for PerEntity<T> {
+impl<T: Storable> AnyStorage for PerEntity<T> {
+	fn component_name(&self) -> &'static str {
+		std::any::type_name::<T>()
+	}
+
 	fn remove_entity(&self, index: usize, top: usize) {
-		let mut borrow = self.borrow_mut();
-		borrow.values.swap_remove(index);
-		debug_assert!(top == borrow.values.len())
+		self.cell.borrow_mut().swap_remove(index, top);
+	}
+
+	fn move_entity(&self, index: usize, top: usize, destination: &mut Archetype, destination_index: usize) {
+		let value = self.cell.borrow_mut().swap_remove(index, top);
+		if destination.get_storage::<Self>().is_none() {
+			destination.components_mut().add(Self::new());
+		}
+		let storage = destination.get_storage_mut::<Self>().unwrap();
+		storage.cell.borrow_mut().insert(destination_index, value);
+	}
+
+	fn add_requirements(&self, requirements: &mut ArchetypeRequirements) {
+		requirements.add_component::<T>()
+	}
+
+	#[inline]
+	fn contains(&self, index: usize) -> bool {
+		index < self.cell.borrow().values.len()
+	}
+
+	fn set_version(&self, version: Version) {
+		self.cell.borrow_mut().version = version;
 	}
 }
 
-impl<T: 'static> ReadableStorage for PerEntity<T> {
-    type Read = Rc<Self>;
+impl<T: Storable> ReadableStorage for PerEntity<T> {
+    type Read = Shared<Self>;
     fn get(_world_storage: &Components, archetype_storage: &Components) -> Option<Self::Read> {
         archetype_storage.get_storage::<Self>()
     }
+    fn add_read_types(types: &mut Vec<StorageType>) {
+        types.push(StorageType::of::<Self>());
+    }
 }
 
-impl<T: 'static> RefLike for PerEntity<T> {
-    type Borrowed = Ref<'static, BorrowedPerEntity<T>>;
-    fn borrow(&self) -> Self::Borrowed {
-        // See also 0a427633-4da0-4729-bae6-45d77542261c
-        unsafe { extend_lifetime(self.cell.borrow()) }
+impl<T: Storable + Clone> ReadableStorageOwned for PerEntity<T> {
+    type Owned = T;
+    fn read_owned(read: &Self::Read, index: usize) -> Option<T> {
+        read.borrow().read(index).cloned()
     }
 }
 
-impl<T: 'static> WritableStorage for PerEntity<T> {
-    type ReadMut = Rc<Self>;
-    fn get_mut(archetype: &Archetype) -> Option<Self::ReadMut> {
+impl<T: Storable> RefLike for PerEntity<T> {
+    type Borrowed<'a> = Ref<'a, BorrowedPerEntity<T>>;
+    fn borrow(&self) -> Self::Borrowed<'_> {
+        self.cell.borrow()
+    }
+}
+
+impl<T: Storable> WritableStorage for PerEntity<T> {
+    type ReadMut = Shared<Self>;
+    fn get_mut(_world_storage: &Components, archetype: &Archetype) -> Option<Self::ReadMut> {
         archetype.get_storage_mut()
     }
+    fn add_write_types(types: &mut Vec<StorageType>) {
+        types.push(StorageType::of::<Self>());
+    }
 }
 
-impl<T: 'static> RefLikeMut for PerEntity<T> {
-    type BorrowedMut = RefMut<'static, BorrowedPerEntity<T>>;
-    fn borrow_mut(&self) -> Self::BorrowedMut {
-        // See also 0a427633-4da0-4729-bae6-45d77542261c
-        unsafe { extend_lifetime(self.cell.borrow_mut()) }
+impl<T: Storable> RefLikeMut for PerEntity<T> {
+    type ItemMut = T;
+    type BorrowedMut<'a> = RefMut<'a, BorrowedPerEntity<T>>;
+    fn borrow_mut(&self) -> Self::BorrowedMut<'_> {
+        self.cell.borrow_mut()
+    }
+    fn current_as_of(&self) -> Version {
+        self.cell.borrow().current_as_of()
     }
 }
 
@@ -129,15 +197,30 @@ impl<T: Component<Storage = PerEntity<T>>> EntityWriterFromComponentStorage for
     #[inline(always)]
     fn write(component: T, archetype: &mut Archetype, index: usize) {
         let storage = archetype.get_storage_mut::<Self>().unwrap();
-        let values = &mut storage.cell.borrow_mut().values;
-        if index == values.len() {
-            values.push(component)
-        } else {
-            values[index] = component;
-        }
+        storage.cell.borrow_mut().insert(index, component);
     }
 
-	fn add_archetype_requirements(_component: &Self::Component, hasher: &mut UnorderedHasher) {
-		hasher.add(&TypeId::of::<T>())
+	fn add_archetype_requirements(_component: &Self::Component, requirements: &mut ArchetypeRequirements) {
+		requirements.add_component::<T>()
 	}
 }
+
+impl<T: Component<Storage = PerEntity<T>>> BorrowFromComponentStorage for PerEntity<T> {
+    type Component = T;
+    fn borrow_component(&self, index: usize) -> Option<Ref<'_, T>> {
+        let borrow = self.cell.borrow();
+        if index >= borrow.values.len() {
+            return None;
+        }
+        Some(Ref::map(borrow, |b| &b.values[index]))
+    }
+}
+
+impl<T: Component<Storage = PerEntity<T>>> EntityReaderFromComponentStorage for PerEntity<T> {
+    type Component = T;
+    fn take(archetype: &Archetype, index: usize, top: usize) -> Option<T> {
+        let storage = archetype.get_storage_mut::<Self>()?;
+        let value = storage.cell.borrow_mut().swap_remove(index, top);
+        Some(value)
+    }
+}
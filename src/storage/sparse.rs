@@ -1,9 +1,146 @@
 use crate::*;
-use extend_lifetime::extend_lifetime;
-use std::cell::{Ref, RefCell};
-use std::collections::HashMap;
-use std::rc::Rc;
-use unordered_hash::UnorderedHasher;
+use crate::sync::{Ref, RefCell, RefMut};
+use crate::sync::Shared;
+use std::ops::Deref;
+
+/// Values for some of the entity indices of an archetype. Values are kept densely packed,
+/// with an index from the entity index to the position of the value.
+pub struct SparseSet<T> {
+    values: Vec<T>,
+    // The entity index of each value
+    slots: Vec<usize>,
+    // The position in values for each entity index
+    positions: Vec<Option<usize>>,
+}
+
+impl<T> SparseSet<T> {
+    pub fn new() -> Self {
+        Self {
+            values: Vec::new(),
+            slots: Vec::new(),
+            positions: Vec::new(),
+        }
+    }
+
+    #[inline]
+    fn position(&self, slot: usize) -> Option<usize> {
+        self.positions.get(slot).copied().flatten()
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    #[inline]
+    pub fn contains(&self, slot: usize) -> bool {
+        self.position(slot).is_some()
+    }
+
+    #[inline]
+    pub fn get(&self, slot: usize) -> Option<&T> {
+        self.position(slot).map(|p| &self.values[p])
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self, slot: usize) -> Option<&mut T> {
+        let position = self.position(slot)?;
+        Some(&mut self.values[position])
+    }
+
+    /// Sets the value for the slot, returning the previous value if there was one.
+    pub fn insert(&mut self, slot: usize, value: T) -> Option<T> {
+        if let Some(position) = self.position(slot) {
+            return Some(std::mem::replace(&mut self.values[position], value));
+        }
+        if slot >= self.positions.len() {
+            self.positions.resize(slot + 1, None);
+        }
+        self.positions[slot] = Some(self.values.len());
+        self.values.push(value);
+        self.slots.push(slot);
+        None
+    }
+
+    pub fn remove(&mut self, slot: usize) -> Option<T> {
+        let position = self.positions.get_mut(slot)?.take()?;
+        let value = self.values.swap_remove(position);
+        self.slots.swap_remove(position);
+        if position < self.slots.len() {
+            self.positions[self.slots[position]] = Some(position);
+        }
+        Some(value)
+    }
+
+    /// Moves the value (if any) from one slot to another slot, which must not have a value.
+    fn relabel(&mut self, from: usize, to: usize) {
+        debug_assert!(!self.contains(to));
+        if let Some(position) = self.positions.get_mut(from).and_then(Option::take) {
+            if to >= self.positions.len() {
+                self.positions.resize(to + 1, None);
+            }
+            self.positions[to] = Some(position);
+            self.slots[position] = to;
+        }
+    }
+
+    /// Iterates over the slots and values in the order they are stored.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &T)> {
+        self.slots.iter().copied().zip(self.values.iter())
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (usize, &mut T)> {
+        self.slots.iter().copied().zip(self.values.iter_mut())
+    }
+}
+
+impl<T> Default for SparseSet<T> {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+/// The SparseSet of an archetype, as written by a process. Values can only be inserted for the
+/// entities of the archetype, so that they are not picked up by entities which are added later.
+pub struct SparseSetMut<'a, T> {
+    values: &'a mut SparseSet<T>,
+    num_entities: usize,
+}
+
+impl<T> SparseSetMut<'_, T> {
+    #[inline]
+    pub fn get_mut(&mut self, slot: usize) -> Option<&mut T> {
+        self.values.get_mut(slot)
+    }
+
+    /// Sets the value for the slot, returning the previous value if there was one.
+    /// Panics if the archetype has no entity at the slot.
+    pub fn insert(&mut self, slot: usize, value: T) -> Option<T> {
+        assert!(slot < self.num_entities, "No entity at slot {} of the archetype", slot);
+        self.values.insert(slot, value)
+    }
+
+    pub fn remove(&mut self, slot: usize) -> Option<T> {
+        self.values.remove(slot)
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (usize, &mut T)> {
+        self.values.iter_mut()
+    }
+}
+
+impl<T> Deref for SparseSetMut<'_, T> {
+    type Target = SparseSet<T>;
+    #[inline]
+    fn deref(&self) -> &SparseSet<T> {
+        self.values
+    }
+}
 
 pub struct Sparse<T> {
     cell: RefCell<BorrowedSparse<T>>,
@@ -11,31 +148,38 @@ pub struct Sparse<T> {
 
 pub struct BorrowedSparse<T> {
     version: Version,
-    values: HashMap<usize, T>,
+    current_as_of: Version,
+    values: SparseSet<T>,
+    // The number of entities in the archetype as of WritableStorage::get_mut
+    num_entities: usize,
 }
 
 impl<T> BorrowedSparse<T> {
     pub fn new() -> Self {
         Self {
             version: Version(0),
-            values: HashMap::new(),
+            current_as_of: Version(0),
+            values: SparseSet::new(),
+            num_entities: 0,
         }
     }
 }
 
-impl<T: 'static> BorrowedStorage for BorrowedSparse<T> {
-    type Item = &'static T;
-    type Batch = &'static HashMap<usize, T>;
+impl<T: Storable> BorrowedStorage for BorrowedSparse<T> {
+    type Item<'a> = &'a T;
+    type Batch<'a> = &'a SparseSet<T>;
+    type EntityItem<'a> = Option<&'a T>;
     fn version(&self) -> Version {
         self.version
     }
-    fn read(&self, index: usize) -> Option<&'static T> {
-        // See also 0a427633-4da0-4729-bae6-45d77542261c
-        unsafe { extend_lifetime(self.values.get(&index)) }
+    fn read(&self, index: usize) -> Option<&T> {
+        self.values.get(index)
+    }
+    fn read_entity(&self, index: usize) -> Option<&T> {
+        self.read(index)
     }
-    fn read_batch(&self) -> Self::Batch {
-        // See also 0a427633-4da0-4729-bae6-45d77542261c
-        unsafe { extend_lifetime(&self.values) }
+    fn read_batch(&self) -> &SparseSet<T> {
+        &self.values
     }
 }
 
@@ -52,55 +196,165 @@ impl<T> Sparse<T> {
     }
 }
 
-impl<T: 'static> AnyStorage for Sparse<T> {
+impl<T> BorrowedSparse<T> {
+	fn swap_remove(&mut self, index: usize, top: usize) -> Option<T> {
+		let value = self.values.remove(index);
+		if top != index {
+			self.values.relabel(top, index);
+		}
+		value
+	}
+}
+
+impl<T: Storable> AnyStorage for Sparse<T> {
+	fn component_name(&self) -> &'static str {
+		std::any::type_name::<T>()
+	}
+
 	fn remove_entity(&self, index: usize, top: usize) {
-		let mut borrow = self.cell.borrow_mut();
-		let top = borrow.values.remove(&top);
-		if let Some(top) = top {
-			borrow.values.insert(index, top);
-		} else {
-			borrow.values.remove(&index);
+		self.cell.borrow_mut().swap_remove(index, top);
+	}
+
+	fn move_entity(&self, index: usize, top: usize, destination: &mut Archetype, destination_index: usize) {
+		if let Some(value) = self.cell.borrow_mut().swap_remove(index, top) {
+			if destination.get_storage::<Self>().is_none() {
+				destination.components_mut().add(Self::new());
+			}
+			let storage = destination.get_storage_mut::<Self>().unwrap();
+			storage.cell.borrow_mut().values.insert(destination_index, value);
 		}
 	}
+
+	#[inline]
+	fn add_requirements(&self, _requirements: &mut ArchetypeRequirements) {}
+
+	#[inline]
+	fn contains(&self, index: usize) -> bool {
+		self.cell.borrow().values.contains(index)
+	}
+
+	fn set_version(&self, version: Version) {
+		self.cell.borrow_mut().version = version;
+	}
 }
 
 impl<T: Component> ReadableStorage for Sparse<T> {
-    type Read = Rc<Self>;
-    fn get(_world_storage: &Components, archetype_storage: &Components) -> Option<Rc<Self>> {
+    type Read = Shared<Self>;
+    fn get(_world_storage: &Components, archetype_storage: &Components) -> Option<Shared<Self>> {
         archetype_storage.get_storage::<Self>()
     }
+    fn add_read_types(types: &mut Vec<StorageType>) {
+        types.push(StorageType::of::<Self>());
+    }
+}
+
+impl<T: Component + Clone> ReadableStorageOwned for Sparse<T> {
+    type Owned = T;
+    fn read_owned(read: &Self::Read, index: usize) -> Option<T> {
+        read.borrow().read(index).cloned()
+    }
 }
 
-impl<T: 'static> RefLike for Sparse<T> {
-    type Borrowed = Ref<'static, BorrowedSparse<T>>;
-    fn borrow(&self) -> Self::Borrowed {
-        // See also 0a427633-4da0-4729-bae6-45d77542261c
-        unsafe { extend_lifetime(self.cell.borrow()) }
+impl<T: Storable> RefLike for Sparse<T> {
+    type Borrowed<'a> = Ref<'a, BorrowedSparse<T>>;
+    fn borrow(&self) -> Self::Borrowed<'_> {
+        self.cell.borrow()
     }
 }
-/*
+
+impl<T: Storable> BorrowedStorageMut for BorrowedSparse<T> {
+    type ItemMut = Option<T>;
+    type BatchMut<'a> = SparseSetMut<'a, T>;
+    /// Inserts or overwrites the value for the entity with Some, or removes it with None.
+    fn write(&mut self, index: usize, item: Option<T>) {
+        let mut values = self.write_batch();
+        match item {
+            Some(value) => values.insert(index, value),
+            None => values.remove(index),
+        };
+    }
+    fn write_batch(&mut self) -> SparseSetMut<'_, T> {
+        SparseSetMut {
+            values: &mut self.values,
+            num_entities: self.num_entities,
+        }
+    }
+    fn set_version(&mut self, version: Version) {
+        self.version = version;
+    }
+    fn current_as_of(&self) -> Version {
+        self.current_as_of
+    }
+    fn set_current_as_of(&mut self, version: Version) {
+        self.current_as_of = version;
+    }
+}
+
 impl<T: Component> WritableStorage for Sparse<T> {
-    /*
-    fn read_mut(archetype: &Archetype, index: usize) -> Option<&mut Self::Read> {
-        archetype.get_storage::<Self>().and_then(|s| unsafe { (&mut *s.values.get()) }.get_mut(&index))
+    type ReadMut = Shared<Self>;
+    fn get_mut(_world_storage: &Components, archetype: &Archetype) -> Option<Self::ReadMut> {
+        let storage = archetype.get_storage_mut::<Self>()?;
+        storage.cell.borrow_mut().num_entities = archetype.num_entities();
+        Some(storage)
     }
-    fn read_batch_mut(archetype: &Archetype) -> Option<&mut Self::BatchRead> {
-        archetype.get_storage::<Self>().map(|s| unsafe { (&mut *s.values.get()) } )
+    fn add_write_types(types: &mut Vec<StorageType>) {
+        types.push(StorageType::of::<Self>());
+    }
+    fn prepare(archetype: &mut Archetype) {
+        // Sparse values do not change the archetype, so writing may need to initialize the storage.
+        if archetype.get_storage::<Self>().is_none() {
+            archetype.components_mut().add(Self::new());
+        }
     }
-    */
 }
-*/
 
+impl<T: Storable> RefLikeMut for Sparse<T> {
+    type ItemMut = Option<T>;
+    type BorrowedMut<'a> = RefMut<'a, BorrowedSparse<T>>;
+    fn borrow_mut(&self) -> Self::BorrowedMut<'_> {
+        self.cell.borrow_mut()
+    }
+    fn current_as_of(&self) -> Version {
+        self.cell.borrow().current_as_of()
+    }
+}
 
 impl<T: Component<Storage = Sparse<T>>> EntityWriterFromComponentStorage for Sparse<T> {
     type Component = T;
     #[inline]
     fn write(component: T, archetype: &mut Archetype, index: usize) {
+        // Sparse values do not change the archetype, so the first value
+        // written may need to initialize the storage.
+        if archetype.get_storage::<Self>().is_none() {
+            archetype.add_storage::<T>(Self::new());
+        }
         let s = archetype.get_storage_mut::<Self>().unwrap();
         s.cell.borrow_mut().values.insert(index, component);
     }
 
-	fn add_archetype_requirements(component: &Self::Component, hasher: &mut UnorderedHasher) {}
+	fn add_archetype_requirements(_component: &Self::Component, requirements: &mut ArchetypeRequirements) {
+		requirements.add_sparse::<T>()
+	}
+}
+
+impl<T: Component<Storage = Sparse<T>>> BorrowFromComponentStorage for Sparse<T> {
+    type Component = T;
+    fn borrow_component(&self, index: usize) -> Option<Ref<'_, T>> {
+        let borrow = self.cell.borrow();
+        if !borrow.values.contains(index) {
+            return None;
+        }
+        Some(Ref::map(borrow, |b| b.values.get(index).unwrap()))
+    }
+}
+
+impl<T: Component<Storage = Sparse<T>>> EntityReaderFromComponentStorage for Sparse<T> {
+    type Component = T;
+    fn take(archetype: &Archetype, index: usize, top: usize) -> Option<T> {
+        let storage = archetype.get_storage_mut::<Self>()?;
+        let mut borrow = storage.cell.borrow_mut();
+        borrow.swap_remove(index, top)
+    }
 }
 
 impl<T: EntityWriter + Component<Storage = Sparse<T>>> ArchetypeInitializerFromComponentStorage
@@ -108,8 +362,6 @@ impl<T: EntityWriter + Component<Storage = Sparse<T>>> ArchetypeInitializerFromC
 {
     type Component = T;
     fn initialize(component: T, archetype: &mut Archetype) {
-        let storage = Self::new();
-        archetype.add_storage::<T>(storage);
         component.write(archetype, 0);
     }
 }
@@ -0,0 +1,130 @@
+//! Shared ownership and interior mutability of storages. These are Rc and RefCell, unless the
+//! parallel feature is enabled, in which case they are thread-safe equivalents with the same API.
+
+#[cfg(not(feature = "parallel"))]
+mod inner {
+    pub use downcast_rs::Downcast as SharedDowncast;
+    pub use std::cell::{Ref, RefCell, RefMut};
+    pub use std::rc::Rc as Shared;
+
+    /// Bounds for types which are shared between threads when the parallel feature is enabled.
+    pub trait Parallel {}
+    impl<T: ?Sized> Parallel for T {}
+}
+
+#[cfg(feature = "parallel")]
+mod inner {
+    pub use downcast_rs::DowncastSync as SharedDowncast;
+    use std::cell::UnsafeCell;
+    use std::ops::{Deref, DerefMut};
+    pub use std::sync::Arc as Shared;
+    use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError};
+
+    /// Bounds for types which are shared between threads when the parallel feature is enabled.
+    pub trait Parallel: Send + Sync {}
+    impl<T: ?Sized + Send + Sync> Parallel for T {}
+
+    /// Like std::cell::RefCell, a conflicting borrow panics rather than waiting for the other to be
+    /// released, whichever thread holds it.
+    pub struct RefCell<T> {
+        lock: RwLock<()>,
+        value: UnsafeCell<T>,
+    }
+
+    unsafe impl<T: Send> Send for RefCell<T> {}
+    unsafe impl<T: Send + Sync> Sync for RefCell<T> {}
+
+    impl<T> RefCell<T> {
+        pub fn new(value: T) -> Self {
+            Self {
+                lock: RwLock::new(()),
+                value: UnsafeCell::new(value),
+            }
+        }
+
+        pub fn borrow(&self) -> Ref<'_, T> {
+            let guard = match self.lock.try_read() {
+                Ok(guard) => guard,
+                Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+                Err(TryLockError::WouldBlock) => panic!("already mutably borrowed"),
+            };
+            Ref {
+                value: unsafe { &*self.value.get() },
+                _guard: guard,
+            }
+        }
+
+        pub fn borrow_mut(&self) -> RefMut<'_, T> {
+            let guard = match self.lock.try_write() {
+                Ok(guard) => guard,
+                Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+                Err(TryLockError::WouldBlock) => panic!("already borrowed"),
+            };
+            RefMut {
+                value: unsafe { &mut *self.value.get() },
+                _guard: guard,
+            }
+        }
+
+        pub fn into_inner(self) -> T {
+            self.value.into_inner()
+        }
+    }
+
+    pub struct Ref<'a, T: ?Sized> {
+        value: &'a T,
+        _guard: RwLockReadGuard<'a, ()>,
+    }
+
+    impl<'a, T: ?Sized> Ref<'a, T> {
+        pub fn map<U: ?Sized>(orig: Ref<'a, T>, f: impl FnOnce(&T) -> &U) -> Ref<'a, U> {
+            Ref {
+                value: f(orig.value),
+                _guard: orig._guard,
+            }
+        }
+    }
+
+    impl<T: ?Sized> Deref for Ref<'_, T> {
+        type Target = T;
+        #[inline(always)]
+        fn deref(&self) -> &T {
+            self.value
+        }
+    }
+
+    pub struct RefMut<'a, T: ?Sized> {
+        value: &'a mut T,
+        _guard: RwLockWriteGuard<'a, ()>,
+    }
+
+    impl<'a, T: ?Sized> RefMut<'a, T> {
+        pub fn map<U: ?Sized>(orig: RefMut<'a, T>, f: impl FnOnce(&mut T) -> &mut U) -> RefMut<'a, U> {
+            RefMut {
+                value: f(orig.value),
+                _guard: orig._guard,
+            }
+        }
+    }
+
+    impl<T: ?Sized> Deref for RefMut<'_, T> {
+        type Target = T;
+        #[inline(always)]
+        fn deref(&self) -> &T {
+            self.value
+        }
+    }
+
+    impl<T: ?Sized> DerefMut for RefMut<'_, T> {
+        #[inline(always)]
+        fn deref_mut(&mut self) -> &mut T {
+            self.value
+        }
+    }
+}
+
+pub use inner::*;
+
+/// Bounds for the values stored in the World.
+pub trait Storable: Parallel + 'static {}
+impl<T: ?Sized + Parallel + 'static> Storable for T {}
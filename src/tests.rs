@@ -1,5 +1,7 @@
 use crate::*;
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
 macro_rules! entities {
     ($($x:expr,)*) => (
@@ -41,6 +43,31 @@ impl Component for Kind {
     type Storage = PerArchetype<Self>;
 }
 
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+struct Marked(u8);
+impl Component for Marked {
+    type Storage = Sparse<Self>;
+}
+
+// Every value has the same hash, to check that archetypes do not rely on the hash alone.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+struct Colliding(u8);
+impl std::hash::Hash for Colliding {
+    fn hash<H: std::hash::Hasher>(&self, _state: &mut H) {}
+}
+impl Component for Colliding {
+    type Storage = PerArchetype<Self>;
+}
+
+struct ArchetypeCountQuery {}
+impl Query for ArchetypeCountQuery {
+    type Reads = UniqueId;
+    type Output = usize;
+    fn execute(&self, data: QueryData<Self::Reads>) -> Self::Output {
+        data.count()
+    }
+}
+
 struct EntityCountsQuery {}
 impl Query for EntityCountsQuery {
     type Reads = (SourceId, UniqueId);
@@ -67,7 +94,7 @@ impl CullArchetypes for CullSamplingRate {
 struct IncreaseLevelK {}
 impl Process for IncreaseLevelK {
     type Reads = Option<Kind>;
-    type Writes = Level; // TODO: It should not be possible to have RW access to data.
+    type Writes = Level;
     fn execute(&self, read: Option<&Kind>, write: &mut [Level]) {
         let add = match read {
             None => 1,
@@ -101,14 +128,14 @@ fn can_add_rich_entity() {
 
     assert_eq!(
         world.read_component::<(Kind, Level)>(&uid),
-        Some((&Kind("k"), &Level(1)))
+        Some((Kind("k"), Level(1)))
     );
     assert_eq!(world.read_component::<SamplingRate>(&uid), None);
 }
 
 #[test]
 fn can_execute_query() {
-    let world = entities! {
+    let mut world = entities! {
         SourceId(0),
         SourceId(1),
         SourceId(1),
@@ -118,8 +145,9 @@ fn can_execute_query() {
         SourceId(1),
     };
 
-    // TODO: Query should be async. Call world.run()
-    let counts = world.execute_query(&EntityCountsQuery {});
+    let counts = world.schedule_query(EntityCountsQuery {});
+    world.run();
+    let counts = block_on(counts);
     assert_eq!(counts.len(), 2);
     assert_eq!(counts.get(&SourceId(0)), Some(&2));
     assert_eq!(counts.get(&SourceId(1)), Some(&5));
@@ -136,27 +164,160 @@ fn can_execute_update() {
         SamplingRate(3),
     };
 
-    // TODO: Update should be async. Call world.run()
     assert_eq!(world.entity_count(), 6);
-    world.execute_update(&CullSamplingRate(SamplingRate(2)));
+    world.schedule_update(CullSamplingRate(SamplingRate(2)));
+    assert_eq!(world.entity_count(), 6);
+    world.run();
     assert_eq!(world.entity_count(), 2);
 }
 
-// TODO: Add sparse zst to entity and ensure it stays in the same archetype, and will initialize the storage for the archetype
-// TODO: Add a sparse zst to an existing entity in an archetype which already has storage, and ensure that works as well.
+#[test]
+fn update_which_culls_nothing_keeps_entities() {
+    let mut world = entities! {
+        (Level(0), SamplingRate(1)),
+        (Level(1), SamplingRate(2)),
+    };
+
+    world.execute_update(&CullSamplingRate(SamplingRate(3)));
+    assert_levels(&world, &[(0, 0), (1, 1)]);
+
+    world.execute_update(&CullSamplingRate(SamplingRate(2)));
+    assert_levels(&world, &[(0, 0)]);
+}
+
+struct LevelsQuery {}
+impl Query for LevelsQuery {
+    type Reads = Level;
+    type Output = usize;
+    fn execute(&self, data: QueryData<Self::Reads>) -> Self::Output {
+        data.map(|levels| levels.iter().map(|l| l.0).sum::<usize>()).sum()
+    }
+}
+
+#[test]
+fn scheduled_work_runs_in_the_order_it_conflicts() {
+    let mut world = entities! {
+        Level(1),
+        (Level(2), Kind("k")),
+    };
+    world.insert_global(LevelStats::default());
+
+    let mut before = world.schedule_query(LevelsQuery {});
+    world.schedule_process(IncreaseLevel {});
+    let after = world.schedule_query(LevelsQuery {});
+    world.schedule_process(CollectLevelStats {});
+    assert_eq!(before.try_take(), None);
+
+    world.run();
+    assert_eq!(block_on(before), 1 + 2);
+    assert_eq!(block_on(after), 2 + 3);
+    assert_eq!(world.global::<LevelStats>().unwrap().total, 5);
+
+    // Nothing is left to run
+    world.run();
+    assert_eq!(world.read_component::<Level>(&UniqueId(0)), Some(Level(2)));
+}
+
 // TODO: A way to specify to initialize a derived component from a process when it's not there (perhaps the default?). Should that also automatically delete components when other components are deleted?
 //       This could just be to have an update that adds and removes them.
 // TODO: Write a process that removes archetypes with no components
 
-/* TODO:
 #[test]
 fn can_add_component_to_entity() {
-    // Move an entity from one archetype to another by...
-    // Moving the entity out of the archetype as a tuple eg: remove_enity returns (entity_data...)
-    // Append to the tuple
-    // Move the entity back into the system.
+    let mut world = entities! {
+        Level(0),
+        Level(1),
+        (Level(2), Kind("k")),
+    };
+    assert_eq!(world.execute_query(&ArchetypeCountQuery {}), 2);
+
+    world.insert_component(UniqueId(0), Kind("k"));
+    assert_eq!(world.execute_query(&ArchetypeCountQuery {}), 2);
+    assert_eq!(
+        world.read_component::<(Level, Kind)>(&UniqueId(0)),
+        Some((Level(0), Kind("k")))
+    );
+    assert_eq!(world.read_component::<Level>(&UniqueId(1)), Some(Level(1)));
+
+    // Replacing a PerEntity value leaves the entity in place.
+    world.insert_component(UniqueId(0), Level(10));
+    assert_eq!(world.read_component::<Level>(&UniqueId(0)), Some(Level(10)));
+
+    // Moves the last remaining entity into a new archetype.
+    world.insert_component(UniqueId(1), Kind("x"));
+    assert_eq!(world.execute_query(&ArchetypeCountQuery {}), 2);
+    assert_eq!(
+        world.read_component::<(Level, Kind)>(&UniqueId(1)),
+        Some((Level(1), Kind("x")))
+    );
+    assert_levels(&world, &[(0, 10), (1, 1), (2, 2)]);
+}
+
+#[test]
+fn can_remove_component_from_entity() {
+    let mut world = entities! {
+        (Level(0), Kind("k")),
+        (Level(1), Kind("k")),
+        Level(2),
+    };
+
+    assert_eq!(world.remove_component::<Kind>(UniqueId(0)), Some(Kind("k")));
+    assert_eq!(world.remove_component::<Kind>(UniqueId(0)), None);
+    assert_eq!(world.read_component::<Kind>(&UniqueId(0)), None);
+    assert_eq!(world.read_component::<Kind>(&UniqueId(1)), Some(Kind("k")));
+    assert_levels(&world, &[(0, 0), (1, 1), (2, 2)]);
+
+    assert_eq!(world.remove_component::<Level>(UniqueId(2)), Some(Level(2)));
+    assert_eq!(world.read_component::<UniqueId>(&UniqueId(2)), Some(UniqueId(2)));
+    assert_eq!(world.read_component::<Level>(&UniqueId(2)), None);
+    assert_eq!(world.entity_count(), 3);
+}
+
+#[test]
+fn changing_per_archetype_value_moves_entity() {
+    let mut world = entities! {
+        SourceId(0),
+        SourceId(0),
+        SourceId(1),
+    };
+
+    world.insert_component(UniqueId(0), SourceId(1));
+    let counts = world.execute_query(&EntityCountsQuery {});
+    assert_eq!(counts.get(&SourceId(0)), Some(&1));
+    assert_eq!(counts.get(&SourceId(1)), Some(&2));
+    assert_eq!(world.read_component::<SourceId>(&UniqueId(0)), Some(SourceId(1)));
+    assert_eq!(world.read_component::<SourceId>(&UniqueId(1)), Some(SourceId(0)));
+}
+
+#[test]
+fn sparse_components_stay_in_archetype() {
+    let mut world = entities! {
+        Level(0),
+        (Level(1), Marked(1)),
+        Level(2),
+    };
+    assert_eq!(world.execute_query(&ArchetypeCountQuery {}), 1);
+
+    // Initializes the sparse storage for an archetype which did not have it
+    world.insert_component(UniqueId(0), Marked(0));
+    // Adds to storage which already exists
+    world.insert_component(UniqueId(2), Marked(2));
+    assert_eq!(world.execute_query(&ArchetypeCountQuery {}), 1);
+    assert_eq!(world.read_component::<Marked>(&UniqueId(0)), Some(Marked(0)));
+    assert_eq!(world.read_component::<Marked>(&UniqueId(2)), Some(Marked(2)));
+
+    assert_eq!(world.remove_component::<Marked>(UniqueId(0)), Some(Marked(0)));
+    assert_eq!(world.read_component::<Marked>(&UniqueId(0)), None);
+    assert_eq!(world.read_component::<Marked>(&UniqueId(1)), Some(Marked(1)));
+
+    // Sparse values follow the entity when it moves to another archetype.
+    world.insert_component(UniqueId(1), Kind("k"));
+    assert_eq!(
+        world.read_component::<(Kind, Marked)>(&UniqueId(1)),
+        Some((Kind("k"), Marked(1)))
+    );
+    assert_eq!(world.read_component::<Marked>(&UniqueId(2)), Some(Marked(2)));
 }
-*/
 
 #[test]
 fn can_write_system() {
@@ -166,16 +327,1187 @@ fn can_write_system() {
     };
 
     world.execute_process(&IncreaseLevelK {});
-    assert_eq!(world.read_component::<Level>(&UniqueId(0)), Some(&Level(2)));
+    assert_eq!(world.read_component::<Level>(&UniqueId(0)), Some(Level(2)));
     assert_eq!(
         world.read_component::<Level>(&UniqueId(1)),
-        Some(&Level(11))
+        Some(Level(11))
     );
 
     world.execute_process(&IncreaseLevel {});
-    assert_eq!(world.read_component::<Level>(&UniqueId(0)), Some(&Level(3)));
+    assert_eq!(world.read_component::<Level>(&UniqueId(0)), Some(Level(3)));
     assert_eq!(
         world.read_component::<Level>(&UniqueId(1)),
-        Some(&Level(12))
+        Some(Level(12))
+    );
+}
+
+fn assert_levels(world: &World, expected: &[(u128, usize)]) {
+    assert_eq!(world.entity_count(), expected.len());
+    for &(id, level) in expected {
+        assert_eq!(
+            world.read_component::<(UniqueId, Level)>(&UniqueId(id)),
+            Some((UniqueId(id), Level(level)))
+        );
+    }
+}
+
+#[test]
+fn can_remove_entity() {
+    let mut world = entities! {
+        Level(0),
+        Level(1),
+        Level(2),
+        Level(3),
+        Level(4),
+    };
+
+    // Middle
+    world.remove_entity(UniqueId(2));
+    assert_levels(&world, &[(0, 0), (1, 1), (3, 3), (4, 4)]);
+    assert_eq!(world.read_component::<Level>(&UniqueId(2)), None);
+
+    // Front
+    world.remove_entity(UniqueId(0));
+    assert_levels(&world, &[(1, 1), (3, 3), (4, 4)]);
+
+    // Back
+    world.remove_entity(UniqueId(4));
+    assert_levels(&world, &[(1, 1), (3, 3)]);
+
+    world.remove_entity(UniqueId(1));
+    world.remove_entity(UniqueId(3));
+    assert_levels(&world, &[]);
+}
+
+#[test]
+fn can_take_entity() {
+    let mut world = entities! {
+        (Level(0), SourceId(7)),
+        (Level(1), SourceId(7), Marked(1)),
+        (Level(2), SourceId(7)),
+    };
+
+    assert_eq!(
+        world.take_entity::<(Level, SourceId)>(UniqueId(4)),
+        Err(Error::EntityNotFound(UniqueId(4)))
+    );
+    // Missing components of the entity
+    let mut missing = match world.take_entity::<Level>(UniqueId(1)) {
+        Err(Error::ComponentsMismatch { entity, components }) => {
+            assert_eq!(entity, UniqueId(1));
+            components
+        }
+        other => panic!("{:?}", other),
+    };
+    missing.sort();
+    let mut expected = vec![std::any::type_name::<SourceId>(), std::any::type_name::<Marked>()];
+    expected.sort();
+    assert_eq!(missing, expected);
+    // Requesting a component the entity does not have
+    assert_eq!(
+        world.take_entity::<(Level, SourceId, Marked)>(UniqueId(0)),
+        Err(Error::ComponentsMismatch {
+            entity: UniqueId(0),
+            components: vec![std::any::type_name::<Marked>()]
+        })
+    );
+    // Requesting a component twice
+    assert_eq!(
+        world.take_entity::<(Level, SourceId, SourceId)>(UniqueId(0)),
+        Err(Error::ComponentsMismatch {
+            entity: UniqueId(0),
+            components: vec![std::any::type_name::<SourceId>()]
+        })
+    );
+    assert_eq!(world.entity_count(), 3);
+
+    let taken = world.take_entity::<(Level, SourceId, Marked)>(UniqueId(1));
+    assert_eq!(taken, Ok((Level(1), SourceId(7), Marked(1))));
+    assert_levels(&world, &[(0, 0), (2, 2)]);
+    assert_eq!(world.read_component::<Marked>(&UniqueId(2)), None);
+
+    // Change the entity and move it to another world.
+    let (level, source_id, marked) = taken.unwrap();
+    let mut other = World::new();
+    other.add_entity(UniqueId(1), (Level(level.0 + 1), source_id, marked));
+    assert_eq!(
+        other.read_component::<(Level, SourceId, Marked)>(&UniqueId(1)),
+        Some((Level(2), SourceId(7), Marked(1)))
+    );
+}
+
+#[test]
+fn can_reuse_removed_archetypes() {
+    let mut world = entities! {
+        (Level(0), SamplingRate(1)),
+        (Level(1), SamplingRate(2)),
+        (Level(2), SamplingRate(3)),
+    };
+
+    world.remove_entity(UniqueId(0));
+    world.execute_update(&CullSamplingRate(SamplingRate(3)));
+    assert_eq!(world.read_component::<Level>(&UniqueId(2)), None);
+    assert_levels(&world, &[(1, 1)]);
+
+    world.add_entity(UniqueId(3), (Level(3), SamplingRate(3)));
+    world.add_entity(UniqueId(4), (Level(4), SamplingRate(1)));
+    world.add_entity(UniqueId(5), (Level(5), SamplingRate(2)));
+    assert_eq!(world.execute_query(&ArchetypeCountQuery {}), 3);
+    assert_levels(&world, &[(1, 1), (3, 3), (4, 4), (5, 5)]);
+    assert_eq!(
+        world.read_component::<SamplingRate>(&UniqueId(5)),
+        Some(SamplingRate(2))
+    );
+}
+
+#[test]
+fn archetypes_do_not_collide() {
+    let mut world = entities! {
+        (Level(0), SourceId(1)),
+        (Level(1), SamplingRate(1)),
+        (Level(2), Colliding(1)),
+        (Level(3), Colliding(2)),
+    };
+    assert_eq!(world.execute_query(&ArchetypeCountQuery {}), 4);
+
+    world.insert_component(UniqueId(2), Colliding(2));
+    assert_eq!(world.execute_query(&ArchetypeCountQuery {}), 3);
+    assert_eq!(world.read_component::<Colliding>(&UniqueId(2)), Some(Colliding(2)));
+    assert_eq!(world.read_component::<SourceId>(&UniqueId(0)), Some(SourceId(1)));
+    assert_eq!(world.read_component::<SourceId>(&UniqueId(1)), None);
+    assert_levels(&world, &[(0, 0), (1, 1), (2, 2), (3, 3)]);
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
+struct Channel(u8);
+impl Component for Channel {
+    type Storage = PerArchetype<Self>;
+}
+
+fn key_hash(values: (SamplingRate, Channel)) -> u64 {
+    use std::hash::{Hash, Hasher};
+    let mut requirements = ArchetypeRequirements::new();
+    requirements.add_value(&values.0);
+    requirements.add_value(&values.1);
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    requirements.finish().hash(&mut hasher);
+    hasher.finish()
+}
+
+#[test]
+fn archetype_values_are_hashed_with_their_component() {
+    assert_ne!(
+        key_hash((SamplingRate(1), Channel(2))),
+        key_hash((SamplingRate(2), Channel(1)))
+    );
+}
+
+struct BatchSizesQuery {}
+impl Query for BatchSizesQuery {
+    type Reads = UniqueId;
+    type Output = Vec<usize>;
+    fn execute(&self, data: QueryData<Self::Reads>) -> Self::Output {
+        let mut result: Vec<_> = data.map(|ids| ids.len()).collect();
+        result.sort();
+        result
+    }
+}
+
+#[test]
+fn archetypes_are_split_into_chunks() {
+    let mut world = World::new();
+    let count = CAPACITY * 2 + 1;
+    for i in 0..count {
+        world.add_entity(UniqueId(i as u128), Level(i));
+    }
+    assert_eq!(
+        world.execute_query(&BatchSizesQuery {}),
+        vec![1, CAPACITY, CAPACITY]
+    );
+
+    // Removal compacts within the chunk
+    world.remove_entity(UniqueId(0));
+    world.remove_entity(UniqueId(CAPACITY as u128 + 1));
+    world.remove_entity(UniqueId(count as u128 - 1));
+    assert_eq!(
+        world.execute_query(&BatchSizesQuery {}),
+        vec![CAPACITY - 1, CAPACITY - 1]
+    );
+
+    // Fills chunks with room before creating another
+    for i in count..count + 3 {
+        world.add_entity(UniqueId(i as u128), Level(i));
+    }
+    assert_eq!(
+        world.execute_query(&BatchSizesQuery {}),
+        vec![1, CAPACITY, CAPACITY]
+    );
+
+    world.execute_process(&IncreaseLevel {});
+    for i in (1..count - 1).chain(count..count + 3) {
+        if i != CAPACITY + 1 {
+            assert_eq!(world.read_component::<Level>(&UniqueId(i as u128)), Some(Level(i + 1)));
+        }
+    }
+}
+
+// Marks entities with an even level, and unmarks the rest.
+struct MarkEven {}
+impl Process for MarkEven {
+    type Reads = Level;
+    type Writes = Marked;
+    fn execute(&self, read: &[Level], mut write: SparseSetMut<Marked>) {
+        for (index, level) in read.iter().enumerate() {
+            if level.0 % 2 == 0 {
+                write.insert(index, Marked(level.0 as u8));
+            } else {
+                write.remove(index);
+            }
+        }
+    }
+}
+
+#[test]
+fn can_write_sparse_components() {
+    let mut world = entities! {
+        Level(0),
+        (Level(1), Marked(100)),
+        Level(2),
+        (Level(3), Kind("k")),
+        (Level(4), Kind("k"), Marked(100)),
+    };
+    assert_eq!(world.execute_query(&ArchetypeCountQuery {}), 2);
+
+    world.execute_process(&MarkEven {});
+    assert_eq!(world.execute_query(&ArchetypeCountQuery {}), 2);
+    assert_eq!(world.read_component::<Marked>(&UniqueId(0)), Some(Marked(0)));
+    assert_eq!(world.read_component::<Marked>(&UniqueId(1)), None);
+    assert_eq!(world.read_component::<Marked>(&UniqueId(2)), Some(Marked(2)));
+    assert_eq!(world.read_component::<Marked>(&UniqueId(3)), None);
+    assert_eq!(world.read_component::<Marked>(&UniqueId(4)), Some(Marked(4)));
+
+    // Values written by a process move with the entity
+    world.remove_entity(UniqueId(0));
+    assert_eq!(world.read_component::<Marked>(&UniqueId(2)), Some(Marked(2)));
+}
+
+// Marks the slot after the last entity of each archetype.
+struct MarkPastEnd {}
+impl Process for MarkPastEnd {
+    type Reads = Level;
+    type Writes = Marked;
+    fn execute(&self, read: &[Level], mut write: SparseSetMut<Marked>) {
+        write.insert(read.len(), Marked(99));
+    }
+}
+
+#[test]
+#[should_panic(expected = "No entity at slot 1 of the archetype")]
+fn sparse_values_can_not_be_written_past_the_archetype() {
+    let mut world = entities! {
+        Level(0),
+    };
+    world.execute_process(&MarkPastEnd {});
+}
+
+struct MarkedQuery {}
+impl Query for MarkedQuery {
+    type Reads = (Marked, UniqueId);
+    type Output = Vec<(UniqueId, Marked)>;
+    fn execute(&self, data: QueryData<Self::Reads>) -> Self::Output {
+        let mut result = Vec::new();
+        for (marked, ids) in data {
+            for (index, value) in marked.iter() {
+                assert!(marked.contains(index));
+                result.push((ids[index], *value));
+            }
+        }
+        result
+    }
+}
+
+#[test]
+fn can_read_sparse_batch() {
+    let mut world = entities! {
+        (Level(0), Marked(0)),
+        Level(1),
+        (Level(2), Marked(2)),
+        (Level(3), Marked(3)),
+    };
+
+    world.remove_entity(UniqueId(0));
+    world.remove_entity(UniqueId(2));
+    world.insert_component(UniqueId(1), Marked(1));
+
+    let mut marked = world.execute_query(&MarkedQuery {});
+    marked.sort_by_key(|(id, _)| id.0);
+    assert_eq!(marked, vec![(UniqueId(1), Marked(1)), (UniqueId(3), Marked(3))]);
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+struct Scale(usize);
+
+struct ScaledLevelsQuery {}
+impl Query for ScaledLevelsQuery {
+    type Reads = (Global<Scale>, Level);
+    type Output = usize;
+    fn execute(&self, data: QueryData<Self::Reads>) -> Self::Output {
+        data.map(|(scale, levels)| levels.iter().map(|l| l.0 * scale.0).sum::<usize>())
+            .sum()
+    }
+}
+
+struct ScaleLevels {}
+impl Process for ScaleLevels {
+    type Reads = Global<Scale>;
+    type Writes = Level;
+    fn execute(&self, scale: &Scale, write: &mut [Level]) {
+        for level in write {
+            *level = Level(level.0 * scale.0);
+        }
+    }
+}
+
+#[test]
+fn can_use_globals() {
+    let mut world = entities! {
+        Level(1),
+        (Level(2), Kind("k")),
+    };
+
+    // Nothing reads a global which has not been inserted
+    assert_eq!(world.execute_query(&ScaledLevelsQuery {}), 0);
+    assert!(world.global::<Scale>().is_none());
+
+    assert_eq!(world.insert_global(Scale(2)), None);
+    assert_eq!(*world.global::<Scale>().unwrap(), Scale(2));
+    assert_eq!(world.execute_query(&ScaledLevelsQuery {}), 6);
+
+    *world.global_mut::<Scale>().unwrap() = Scale(3);
+    assert_eq!(world.execute_query(&ScaledLevelsQuery {}), 9);
+
+    world.execute_process(&ScaleLevels {});
+    assert_levels(&world, &[(0, 3), (1, 6)]);
+
+    assert_eq!(world.insert_global(Scale(10)), Some(Scale(3)));
+    assert_eq!(world.remove_global::<Scale>(), Some(Scale(10)));
+    assert_eq!(world.remove_global::<Scale>(), None);
+    assert_eq!(world.execute_query(&ScaledLevelsQuery {}), 0);
+}
+
+#[derive(Default, Debug, PartialEq)]
+struct LevelStats {
+    archetypes: usize,
+    total: usize,
+}
+
+struct CollectLevelStats {}
+impl Process for CollectLevelStats {
+    type Reads = Level;
+    type Writes = Global<LevelStats>;
+    fn execute(&self, read: &[Level], stats: &mut LevelStats) {
+        // Gives other threads a chance to borrow the global at the same time.
+        std::thread::yield_now();
+        stats.archetypes += 1;
+        stats.total += read.iter().map(|l| l.0).sum::<usize>();
+    }
+}
+
+#[test]
+fn can_write_globals() {
+    let mut world = entities! {
+        Level(1),
+        Level(2),
+        (Level(3), Kind("k")),
+        (Level(4), Kind("x")),
+    };
+
+    // Nothing to write to
+    world.execute_process(&CollectLevelStats {});
+
+    world.insert_global(LevelStats::default());
+    world.execute_process(&CollectLevelStats {});
+    assert_eq!(
+        *world.global::<LevelStats>().unwrap(),
+        LevelStats {
+            archetypes: 3,
+            total: 10
+        }
+    );
+}
+
+#[test]
+fn globals_are_written_by_one_archetype_at_a_time() {
+    let mut world = World::new();
+    for id in 0..64 {
+        world.add_entity(UniqueId(id), (Level(1), SourceId(id)));
+    }
+    world.insert_global(LevelStats::default());
+    world.execute_process(&CollectLevelStats {});
+    assert_eq!(
+        *world.global::<LevelStats>().unwrap(),
+        LevelStats {
+            archetypes: 64,
+            total: 64
+        }
+    );
+}
+
+#[test]
+#[should_panic(expected = "already borrowed")]
+fn conflicting_borrows_panic() {
+    let cell = crate::sync::RefCell::new(0);
+    let _read = cell.borrow();
+    let _write = cell.borrow_mut();
+}
+
+#[test]
+fn versions_track_changes() {
+    let mut world = World::new();
+    assert_eq!(world.version(), Version(0));
+
+    world.add_entity(UniqueId(0), (Level(0), SourceId(0)));
+    let added = world.version();
+    assert!(added > Version(0));
+    assert_eq!(world.read_version::<Level>(&UniqueId(0)), Some(added));
+    assert_eq!(world.read_version::<SourceId>(&UniqueId(0)), Some(added));
+    assert_eq!(world.read_version::<Kind>(&UniqueId(0)), None);
+
+    // A process only stamps what it writes
+    world.add_entity(UniqueId(1), (Level(1), SourceId(1), Kind("k")));
+    world.execute_process(&IncreaseLevelK {});
+    let processed = world.version();
+    assert!(processed > added);
+    assert_eq!(world.read_version::<Level>(&UniqueId(0)), Some(processed));
+    assert_eq!(world.read_version::<SourceId>(&UniqueId(0)), Some(added));
+    assert!(world.read_version::<Kind>(&UniqueId(1)).unwrap() < processed);
+    assert_eq!(
+        world.read_version::<(SourceId, Level)>(&UniqueId(0)),
+        Some(processed)
     );
+
+    // Removal does not stamp
+    world.remove_entity(UniqueId(1));
+    assert_eq!(world.version(), processed);
+
+    world.execute_update(&CullSamplingRate(SamplingRate(0)));
+    assert!(world.version() > processed);
+    assert_eq!(world.read_version::<Level>(&UniqueId(0)), Some(processed));
+
+    world.insert_component(UniqueId(0), Level(5));
+    assert_eq!(world.read_version::<Level>(&UniqueId(0)), Some(world.version()));
+    assert_eq!(world.read_version::<SourceId>(&UniqueId(0)), Some(added));
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+struct DoubleLevel(usize);
+impl Component for DoubleLevel {
+    type Storage = PerEntity<Self>;
+}
+
+struct DeriveDoubleLevel {
+    runs: Arc<AtomicUsize>,
+}
+impl Process for DeriveDoubleLevel {
+    type Reads = Level;
+    type Writes = DoubleLevel;
+    fn execute(&self, read: &[Level], write: &mut [DoubleLevel]) {
+        self.runs.fetch_add(1, Ordering::Relaxed);
+        for (level, double) in read.iter().zip(write.iter_mut()) {
+            *double = DoubleLevel(level.0 * 2);
+        }
+    }
+}
+
+#[test]
+fn derived_components_are_recomputed_lazily() {
+    let mut world = entities! {
+        (Level(1), DoubleLevel::default()),
+        (Level(2), DoubleLevel::default(), Kind("k")),
+    };
+    let runs = Arc::new(AtomicUsize::new(0));
+    world.register_derived(DeriveDoubleLevel { runs: runs.clone() });
+    assert_eq!(runs.load(Ordering::Relaxed), 0);
+
+    assert_eq!(world.read_component::<DoubleLevel>(&UniqueId(0)), Some(DoubleLevel(2)));
+    assert_eq!(runs.load(Ordering::Relaxed), 1);
+    assert_eq!(world.read_component::<DoubleLevel>(&UniqueId(0)), Some(DoubleLevel(2)));
+    assert_eq!(world.read_component::<DoubleLevel>(&UniqueId(1)), Some(DoubleLevel(4)));
+    assert_eq!(runs.load(Ordering::Relaxed), 2);
+
+    // Only the archetype with a changed input is recomputed
+    world.insert_component(UniqueId(0), Level(5));
+    assert_eq!(world.read_component::<DoubleLevel>(&UniqueId(0)), Some(DoubleLevel(10)));
+    assert_eq!(world.read_component::<DoubleLevel>(&UniqueId(1)), Some(DoubleLevel(4)));
+    assert_eq!(runs.load(Ordering::Relaxed), 3);
+
+    // Changes made by processes are seen, and queries are up to date
+    world.execute_process(&IncreaseLevel {});
+    assert_eq!(runs.load(Ordering::Relaxed), 3);
+    assert_eq!(world.execute_query(&DoubleLevelsQuery {}), 12 + 6);
+    assert_eq!(runs.load(Ordering::Relaxed), 5);
+    assert_eq!(world.execute_query(&DoubleLevelsQuery {}), 12 + 6);
+    assert_eq!(runs.load(Ordering::Relaxed), 5);
+
+    // New entities are derived
+    world.add_entity(UniqueId(2), (Level(7), DoubleLevel::default()));
+    assert_eq!(world.read_component::<DoubleLevel>(&UniqueId(2)), Some(DoubleLevel(14)));
+    assert_eq!(world.read_component::<DoubleLevel>(&UniqueId(0)), Some(DoubleLevel(12)));
+}
+
+#[test]
+fn components_can_be_read_while_a_derived_component_is_borrowed() {
+    let mut world = entities! {
+        (Level(1), DoubleLevel::default()),
+    };
+    world.register_derived(DeriveDoubleLevel { runs: Arc::new(AtomicUsize::new(0)) });
+
+    let double = world.component::<DoubleLevel>(&UniqueId(0)).unwrap();
+    assert_eq!(world.read_component::<Level>(&UniqueId(0)), Some(Level(1)));
+    assert_eq!(world.read_component::<DoubleLevel>(&UniqueId(0)), Some(DoubleLevel(2)));
+    assert_eq!(world.execute_query(&DoubleLevelsQuery {}), 2);
+    assert_eq!(*double, DoubleLevel(2));
+}
+
+struct DoubleLevelsQuery {}
+impl Query for DoubleLevelsQuery {
+    type Reads = DoubleLevel;
+    type Output = usize;
+    fn execute(&self, data: QueryData<Self::Reads>) -> Self::Output {
+        data.map(|doubles| doubles.iter().map(|d| d.0).sum::<usize>())
+            .sum()
+    }
+}
+
+struct ChangedLevelsQuery {
+    since: Version,
+}
+impl Query for ChangedLevelsQuery {
+    type Reads = (Changed<Level>, UniqueId);
+    type Output = Vec<u128>;
+    fn execute(&self, data: QueryData<Self::Reads>) -> Self::Output {
+        let mut ids: Vec<_> = data.flat_map(|(_, ids)| ids.iter().map(|id| id.0)).collect();
+        ids.sort();
+        ids
+    }
+    fn changed_since(&self) -> Version {
+        self.since
+    }
+}
+
+struct IncreaseChangedLevel {
+    since: Version,
+}
+impl Process for IncreaseChangedLevel {
+    type Reads = Changed<Kind>;
+    type Writes = Level;
+    fn execute(&self, _read: &Kind, write: &mut [Level]) {
+        for level in write {
+            *level = Level(level.0 + 1);
+        }
+    }
+    fn changed_since(&self) -> Version {
+        self.since
+    }
+}
+
+#[test]
+fn changed_skips_unmodified_archetypes() {
+    let mut world = entities! {
+        Level(1),
+        (Level(2), Kind("k")),
+        (Level(3), Kind("x")),
+    };
+    assert_eq!(world.execute_query(&ChangedLevelsQuery { since: Version(0) }), vec![0, 1, 2]);
+
+    let since = world.version();
+    assert_eq!(world.execute_query(&ChangedLevelsQuery { since }), vec![]);
+
+    world.insert_component(UniqueId(1), Level(5));
+    assert_eq!(world.execute_query(&ChangedLevelsQuery { since }), vec![1]);
+
+    // Processes skip archetypes in the same way
+    let since = world.version();
+    world.add_entity(UniqueId(3), (Level(0), Kind("y")));
+    world.execute_process(&IncreaseChangedLevel { since });
+    assert_levels(&world, &[(0, 1), (1, 5), (2, 3), (3, 1)]);
+    assert_eq!(world.execute_query(&ChangedLevelsQuery { since }), vec![3]);
+}
+
+struct FilteredIdsQuery<F> {
+    _marker: std::marker::PhantomData<F>,
+}
+impl<F> FilteredIdsQuery<F> {
+    fn new() -> Self {
+        Self { _marker: std::marker::PhantomData }
+    }
+}
+impl<F: ArchetypeFilter + Default> Query for FilteredIdsQuery<F> {
+    type Reads = UniqueId;
+    type Output = Vec<u128>;
+    fn execute(&self, data: QueryData<Self::Reads>) -> Self::Output {
+        let mut ids: Vec<_> = data.flat_map(|ids| ids.iter().map(|id| id.0)).collect();
+        ids.sort();
+        ids
+    }
+    fn filter(&self) -> impl ArchetypeFilter {
+        F::default()
+    }
+}
+
+struct IncreaseLevelWithoutKind {}
+impl Process for IncreaseLevelWithoutKind {
+    type Reads = ();
+    type Writes = Level;
+    fn execute(&self, _read: (), write: &mut [Level]) {
+        for level in write {
+            *level = Level(level.0 + 1);
+        }
+    }
+    fn filter(&self) -> impl ArchetypeFilter {
+        Without::<Kind>::default()
+    }
+}
+
+struct CullAll {}
+impl CullArchetypes for CullAll {
+    type Reads = ();
+    fn should_cull(&self, _data: ()) -> bool {
+        true
+    }
+    fn filter(&self) -> impl ArchetypeFilter {
+        With::<Kind>::default()
+    }
+}
+
+#[test]
+fn filters_select_archetypes() {
+    let mut world = entities! {
+        Level(0),
+        (Level(1), Kind("k")),
+        (Level(2), SourceId(0)),
+        (Level(3), SourceId(1)),
+        (Level(4), Marked(0)),
+    };
+    assert_eq!(world.execute_query(&FilteredIdsQuery::<(With<Level>, Without<Kind>)>::new()), vec![0, 2, 3, 4]);
+    assert_eq!(world.execute_query(&FilteredIdsQuery::<AnyOf<(Kind, SourceId)>>::new()), vec![1, 2, 3]);
+    // Sparse values do not change the archetype, so With<Marked> includes the unmarked entity too.
+    assert_eq!(world.execute_query(&FilteredIdsQuery::<With<Marked>>::new()), vec![0, 4]);
+
+    world.execute_process(&IncreaseLevelWithoutKind {});
+    world.execute_update(&CullAll {});
+    assert_levels(&world, &[(0, 1), (2, 3), (3, 4), (4, 5)]);
+}
+
+struct SourceLevelsQuery {
+    source: SourceId,
+}
+impl Query for SourceLevelsQuery {
+    type Reads = Level;
+    type Output = Vec<usize>;
+    fn execute(&self, data: QueryData<Self::Reads>) -> Self::Output {
+        data.flat_map(|levels| levels.iter().map(|l| l.0)).collect()
+    }
+    fn filter(&self) -> impl ArchetypeFilter {
+        let source = self.source;
+        Where::new(move |s: &SourceId| *s == source)
+    }
+}
+
+struct IncreaseLowSourceLevels {}
+impl Process for IncreaseLowSourceLevels {
+    type Reads = ();
+    type Writes = Level;
+    fn execute(&self, _read: (), write: &mut [Level]) {
+        for level in write {
+            *level = Level(level.0 + 10);
+        }
+    }
+    fn filter(&self) -> impl ArchetypeFilter {
+        Where::new(|s: &SourceId| s.0 < 2)
+    }
+}
+
+#[test]
+fn where_filters_per_archetype_values() {
+    let mut world = entities! {
+        (Level(0), SourceId(0)),
+        (Level(1), SourceId(1)),
+        (Level(2), SourceId(2)),
+        Level(3),
+    };
+    assert_eq!(world.execute_query(&SourceLevelsQuery { source: SourceId(1) }), vec![1]);
+    assert_eq!(world.execute_query(&SourceLevelsQuery { source: SourceId(3) }), vec![]);
+    assert_eq!(world.execute_query(&FilteredIdsQuery::<Where<SourceId>>::new()), vec![0, 1, 2]);
+
+    world.execute_process(&IncreaseLowSourceLevels {});
+    assert_levels(&world, &[(0, 10), (1, 11), (2, 2), (3, 3)]);
+}
+
+struct EntityItemsQuery {}
+impl Query for EntityItemsQuery {
+    type Reads = (UniqueId, Option<SourceId>, Level, Option<Marked>);
+    type Output = Vec<(u128, Option<SourceId>, usize, Option<Marked>)>;
+    fn execute(&self, data: QueryData<Self::Reads>) -> Self::Output {
+        let mut items: Vec<_> = data
+            .entities()
+            .map(|(id, source, level, marked)| (id.0, source.copied(), level.0, marked.copied()))
+            .collect();
+        items.sort_by_key(|item| item.0);
+        items
+    }
+}
+
+struct MarkedEntitiesQuery {}
+impl Query for MarkedEntitiesQuery {
+    type Reads = (UniqueId, Marked);
+    type Output = Vec<(u128, Option<u8>)>;
+    fn execute(&self, data: QueryData<Self::Reads>) -> Self::Output {
+        let mut items: Vec<_> = data.entities().map(|(id, marked)| (id.0, marked.map(|m| m.0))).collect();
+        items.sort();
+        items
+    }
+}
+
+#[test]
+fn can_iterate_entities() {
+    let world = entities! {
+        Level(0),
+        (Level(1), SourceId(7)),
+        (Level(2), Marked(3)),
+        (Level(3), SourceId(7), Marked(4)),
+        Kind("k"),
+    };
+    assert_eq!(
+        world.execute_query(&EntityItemsQuery {}),
+        vec![
+            (0, None, 0, None),
+            (1, Some(SourceId(7)), 1, None),
+            (2, None, 2, Some(Marked(3))),
+            (3, Some(SourceId(7)), 3, Some(Marked(4))),
+        ]
+    );
+    // Sparse values are given for every entity of an archetype with the storage, set or not
+    assert_eq!(
+        world.execute_query(&MarkedEntitiesQuery {}),
+        vec![(0, None), (1, None), (2, Some(3)), (3, Some(4))]
+    );
+}
+
+struct DoubleLevelSimple {}
+impl ProcessSimple for DoubleLevelSimple {
+    type Reads = (Level, Option<Kind>);
+    type Writes = DoubleLevel;
+    fn execute(&self, (level, kind): (&Level, Option<&Kind>)) -> DoubleLevel {
+        match kind {
+            Some(_) => DoubleLevel(level.0 * 20),
+            None => DoubleLevel(level.0 * 2),
+        }
+    }
+}
+
+#[test]
+fn can_execute_process_simple() {
+    let mut world = entities! {
+        (Level(1), DoubleLevel::default()),
+        (Level(2), DoubleLevel::default(), Kind("k")),
+        (Level(3), DoubleLevel::default()),
+        Level(4),
+    };
+    world.execute_process_simple(&DoubleLevelSimple {});
+    assert_eq!(world.read_component::<DoubleLevel>(&UniqueId(0)), Some(DoubleLevel(2)));
+    assert_eq!(world.read_component::<DoubleLevel>(&UniqueId(1)), Some(DoubleLevel(40)));
+    assert_eq!(world.read_component::<DoubleLevel>(&UniqueId(2)), Some(DoubleLevel(6)));
+    assert_eq!(world.read_component::<DoubleLevel>(&UniqueId(3)), None);
+}
+
+struct MarkKinds {}
+impl ProcessSimple for MarkKinds {
+    type Reads = Kind;
+    type Writes = Marked;
+    fn execute(&self, _kind: &Kind) -> Option<Marked> {
+        Some(Marked(1))
+    }
+}
+
+struct MarkNothing {}
+impl Process for MarkNothing {
+    type Reads = Kind;
+    type Writes = Marked;
+    fn execute(&self, _read: &Kind, _write: SparseSetMut<Marked>) {}
+}
+
+#[test]
+fn writing_sparse_components_only_prepares_archetypes_which_are_read() {
+    let mut world = entities! {
+        Level(0),
+        (Level(1), Kind("k")),
+    };
+    world.execute_process(&MarkNothing {});
+    world.execute_process_simple(&MarkKinds {});
+    assert_eq!(world.read_component::<Marked>(&UniqueId(1)), Some(Marked(1)));
+    assert_eq!(world.execute_query(&FilteredIdsQuery::<With<Marked>>::new()), vec![1]);
+}
+
+struct LevelUpMarked {}
+impl Process for LevelUpMarked {
+    type Reads = Option<Kind>;
+    type Writes = (Level, Option<Marked>);
+    fn execute(&self, read: Option<&Kind>, (levels, marked): (&mut [Level], Option<SparseSetMut<Marked>>)) {
+        for level in levels.iter_mut() {
+            *level = Level(level.0 + 1);
+        }
+        if let (Some(_), Some(mut marked)) = (read, marked) {
+            for (_, m) in marked.iter_mut() {
+                *m = Marked(m.0 + 1);
+            }
+        }
+    }
+}
+
+struct WriteLevelTwice {}
+impl Process for WriteLevelTwice {
+    type Reads = ();
+    type Writes = (Level, Level);
+    fn execute(&self, _read: (), _write: (&mut [Level], &mut [Level])) {}
+}
+
+#[test]
+fn can_write_tuples_and_options() {
+    let mut world = entities! {
+        Level(0),
+        (Level(1), Kind("k")),
+    };
+    world.insert_component(UniqueId(1), Marked(5));
+    world.execute_process(&LevelUpMarked {});
+    assert_levels(&world, &[(0, 1), (1, 2)]);
+    assert_eq!(world.read_component::<Marked>(&UniqueId(1)), Some(Marked(6)));
+    // Optional writes do not add storage
+    assert_eq!(world.read_component::<Option<Marked>>(&UniqueId(0)), Some(None));
+}
+
+#[test]
+#[should_panic(expected = "The same component is written more than once")]
+fn writing_a_component_twice_panics() {
+    let mut world = entities! {
+        Level(0),
+    };
+    world.execute_process(&WriteLevelTwice {});
+}
+
+struct ReadWriteLevel {}
+impl Process for ReadWriteLevel {
+    type Reads = (UniqueId, Level);
+    type Writes = Level;
+    fn execute(&self, _read: (&[UniqueId], &[Level]), _write: &mut [Level]) {}
+}
+
+#[test]
+#[should_panic(expected = "The same component is both read and written")]
+fn reading_a_written_component_panics() {
+    let mut world = entities! {
+        Level(0),
+    };
+    world.execute_process(&ReadWriteLevel {});
+}
+
+// Not Clone, so it can only be read by borrowing it.
+#[derive(Debug, Eq, PartialEq)]
+struct Label(String);
+impl Component for Label {
+    type Storage = PerEntity<Self>;
+}
+
+#[test]
+fn components_can_be_borrowed() {
+    let world = entities! {
+        (Level(1), Label("one".to_string()), Kind("k"), Marked(2)),
+        Level(2),
+    };
+    assert_eq!(world.component::<Label>(&UniqueId(0)).as_deref(), Some(&Label("one".to_string())));
+    assert_eq!(world.component::<Level>(&UniqueId(1)).as_deref(), Some(&Level(2)));
+    assert_eq!(world.component::<Kind>(&UniqueId(0)).as_deref(), Some(&Kind("k")));
+    assert_eq!(world.component::<Marked>(&UniqueId(0)).as_deref(), Some(&Marked(2)));
+    assert!(world.component::<Label>(&UniqueId(1)).is_none());
+    assert!(world.component::<Marked>(&UniqueId(1)).is_none());
+    assert!(world.component::<Level>(&UniqueId(2)).is_none());
+}
+
+#[test]
+fn try_operations_return_errors() {
+    use std::any::type_name;
+    let mut world = entities! {
+        Level(0),
+    };
+    assert_eq!(world.try_add_entity(UniqueId(0), Level(1)), Err(Error::DuplicateEntity(UniqueId(0))));
+    assert_eq!(
+        world.try_add_entity(UniqueId(1), (Level(1), Level(2))),
+        Err(Error::DuplicateComponent {
+            entity: UniqueId(1),
+            component: type_name::<Level>()
+        })
+    );
+    assert_eq!(
+        world.try_add_entity(UniqueId(1), (Level(1), Marked(1), Marked(2))),
+        Err(Error::DuplicateComponent {
+            entity: UniqueId(1),
+            component: type_name::<Marked>()
+        })
+    );
+    assert_eq!(world.entity_count(), 1);
+    assert_eq!(world.try_insert_component(UniqueId(1), Marked(1)), Err(Error::EntityNotFound(UniqueId(1))));
+    assert_eq!(world.try_insert_component(UniqueId(0), UniqueId(1)), Err(Error::UniqueIdReplaced(UniqueId(0))));
+
+    assert_eq!(world.try_read_component::<Level>(&UniqueId(0)), Ok(Level(0)));
+    assert_eq!(
+        world.try_read_component::<Kind>(&UniqueId(0)),
+        Err(Error::ComponentNotFound {
+            entity: UniqueId(0),
+            component: type_name::<Kind>()
+        })
+    );
+    assert_eq!(world.try_read_component::<Level>(&UniqueId(1)), Err(Error::EntityNotFound(UniqueId(1))));
+
+    assert_eq!(
+        world.try_execute_process(&WriteLevelTwice {}),
+        Err(Error::WrittenTwice {
+            component: type_name::<PerEntity<Level>>()
+        })
+    );
+    assert_eq!(
+        world.try_execute_process(&ReadWriteLevel {}),
+        Err(Error::ReadAndWritten {
+            component: type_name::<PerEntity<Level>>()
+        })
+    );
+    assert_eq!(world.try_execute_process(&IncreaseLevel {}), Ok(()));
+    assert_eq!(world.read_component::<Level>(&UniqueId(0)), Some(Level(1)));
+
+    assert_eq!(world.try_remove_entity(UniqueId(0)), Ok(()));
+    assert_eq!(world.try_remove_entity(UniqueId(0)), Err(Error::EntityNotFound(UniqueId(0))));
+    assert_eq!(world.entity_count(), 0);
+}
+
+#[test]
+fn commands_for_removed_entities_are_ignored() {
+    let mut world = entities! {
+        Level(0),
+    };
+    {
+        let mut commands = world.global_mut::<Commands>().unwrap();
+        commands.remove_entity(UniqueId(0));
+        commands.insert_component(UniqueId(0), Level(1));
+        commands.remove_entity(UniqueId(0));
+    }
+    world.apply_commands();
+    assert_eq!(world.entity_count(), 0);
+}
+
+#[test]
+fn commands_adding_a_taken_unique_id_are_ignored() {
+    let mut world = entities! {
+        Level(0),
+    };
+    {
+        let mut commands = world.global_mut::<Commands>().unwrap();
+        commands.add_entity(UniqueId(0), Level(1));
+        commands.add_entity(UniqueId(1), Level(2));
+        commands.add_entity(UniqueId(1), (Level(3), Kind("k")));
+    }
+    world.apply_commands();
+    assert_levels(&world, &[(0, 0), (1, 2)]);
+    assert_eq!(world.read_component::<Kind>(&UniqueId(1)), None);
+}
+
+struct RespawnOddLevels {}
+impl Process for RespawnOddLevels {
+    type Reads = (UniqueId, Level);
+    type Writes = Global<Commands>;
+    fn execute(&self, (ids, levels): (&[UniqueId], &[Level]), commands: &mut Commands) {
+        for (id, level) in ids.iter().zip(levels.iter()) {
+            if level.0 % 2 == 1 {
+                commands.remove_entity(*id);
+                commands.add_entity(UniqueId(id.0 + 10), (Level(level.0 + 1), Kind("respawned")));
+                commands.insert_global(Scale(level.0));
+            }
+        }
+    }
+}
+
+#[test]
+fn commands_are_applied_after_process() {
+    let mut world = entities! {
+        Level(0),
+        Level(1),
+        (Level(2), Kind("k")),
+        (Level(3), Kind("k")),
+        Level(5),
+    };
+    world.execute_process(&RespawnOddLevels {});
+    assert_levels(&world, &[(0, 0), (2, 2), (11, 2), (13, 4), (14, 6)]);
+    assert_eq!(world.read_component::<Kind>(&UniqueId(13)), Some(Kind("respawned")));
+    // Applied in the order queued, archetype by archetype
+    assert_eq!(world.global::<Scale>().map(|s| s.0), Some(3));
+    assert!(world.global::<Commands>().unwrap().is_empty());
+}
+
+struct LogIds {}
+impl Process for LogIds {
+    type Reads = UniqueId;
+    type Writes = Global<Commands>;
+    fn execute(&self, ids: &[UniqueId], commands: &mut Commands) {
+        // Gives other threads a chance to finish first.
+        std::thread::yield_now();
+        for id in ids.iter().map(|id| id.0) {
+            commands.push(move |world: &mut World| world.global_mut::<Vec<u128>>().unwrap().push(id));
+        }
+    }
+}
+
+#[test]
+fn commands_are_applied_in_archetype_order() {
+    let mut world = World::new();
+    for id in 0..64 {
+        world.add_entity(UniqueId(id), (Level(0), SourceId(id)));
+    }
+    // With the parallel feature, the archetypes finish in a different order each time.
+    for _ in 0..20 {
+        world.insert_global(Vec::<u128>::new());
+        world.execute_process(&LogIds {});
+        assert_eq!(*world.global::<Vec<u128>>().unwrap(), (0..64).collect::<Vec<_>>());
+    }
+}
+
+#[test]
+fn scheduled_commands_are_applied_after_each_stage() {
+    let mut world = entities! {
+        Level(1),
+    };
+    world.schedule_process(RespawnOddLevels {});
+    let during = world.schedule_query(LevelsQuery {});
+    world.schedule_process(IncreaseLevel {});
+    let after = world.schedule_query(LevelsQuery {});
+    world.run();
+    // The query shares a stage with the process, so it runs before the respawn.
+    assert_eq!(block_on(during), 1);
+    assert_eq!(block_on(after), 3);
+}
+
+#[test]
+fn scheduled_queries_see_derived_components_up_to_date() {
+    let mut world = entities! {
+        (Level(1), DoubleLevel::default()),
+    };
+    world.register_derived(DeriveDoubleLevel { runs: Arc::new(AtomicUsize::new(0)) });
+    world.schedule_process(IncreaseLevel {});
+    let doubled = world.schedule_query(DoubleLevelsQuery {});
+    world.run();
+    assert_eq!(block_on(doubled), 4);
+}
+
+struct LevelFromDouble {}
+impl Process for LevelFromDouble {
+    type Reads = DoubleLevel;
+    type Writes = Level;
+    fn execute(&self, read: &[DoubleLevel], write: &mut [Level]) {
+        for (double, level) in read.iter().zip(write.iter_mut()) {
+            *level = Level(double.0 / 2);
+        }
+    }
+}
+
+#[test]
+fn schedule_orders_writers_before_readers() {
+    let mut world = entities! {
+        (Level(1), DoubleLevel::default()),
+        (Level(2), DoubleLevel::default(), Kind("k")),
+    };
+    world.insert_global(LevelStats::default());
+    let output = Arc::new(AtomicUsize::new(0));
+    let sink = output.clone();
+    let runs = Arc::new(AtomicUsize::new(0));
+
+    let mut builder = ScheduleBuilder::new();
+    builder
+        .add_query(DoubleLevelsQuery {}, move |total| sink.store(total, Ordering::Relaxed))
+        .add_process(DeriveDoubleLevel { runs: runs.clone() })
+        .add_process(CollectLevelStats {})
+        .add_process(IncreaseLevel {});
+    let mut schedule = builder.build(&world).unwrap();
+    assert_eq!(
+        schedule.stages(),
+        vec![
+            vec![std::any::type_name::<IncreaseLevel>()],
+            vec![
+                std::any::type_name::<DeriveDoubleLevel>(),
+                std::any::type_name::<CollectLevelStats>()
+            ],
+            vec![std::any::type_name::<DoubleLevelsQuery>()],
+        ]
+    );
+
+    schedule.run(&mut world);
+    assert_eq!(output.load(Ordering::Relaxed), 4 + 6);
+    assert_eq!(runs.load(Ordering::Relaxed), 2);
+    assert_eq!(world.global::<LevelStats>().unwrap().total, 5);
+}
+
+#[test]
+fn schedule_orders_derived_components_after_their_inputs() {
+    let mut world = entities! {
+        (Level(1), DoubleLevel::default()),
+        (Level(2), DoubleLevel::default(), Kind("k")),
+    };
+    world.register_derived(DeriveDoubleLevel { runs: Arc::new(AtomicUsize::new(0)) });
+    let output = Arc::new(AtomicUsize::new(0));
+    let sink = output.clone();
+
+    // The query reads DoubleLevel, which is derived from the Level written by the process.
+    let mut builder = ScheduleBuilder::new();
+    builder
+        .add_query(DoubleLevelsQuery {}, move |total| sink.store(total, Ordering::Relaxed))
+        .add_process(IncreaseLevel {});
+    let mut schedule = builder.build(&world).unwrap();
+    assert_eq!(
+        schedule.stages(),
+        vec![
+            vec![std::any::type_name::<IncreaseLevel>()],
+            vec![std::any::type_name::<DoubleLevelsQuery>()],
+        ]
+    );
+
+    schedule.run(&mut world);
+    assert_eq!(output.load(Ordering::Relaxed), 4 + 6);
+}
+
+#[test]
+fn schedule_reports_cycles_and_conflicts() {
+    let world = World::new();
+    let mut builder = ScheduleBuilder::new();
+    builder
+        .add_process(IncreaseLevel {})
+        .add_process(DeriveDoubleLevel { runs: Arc::new(AtomicUsize::new(0)) })
+        .add_process(LevelFromDouble {});
+    assert_eq!(
+        builder.build(&world).err(),
+        Some(ScheduleError::Cycle(vec![
+            std::any::type_name::<DeriveDoubleLevel>(),
+            std::any::type_name::<LevelFromDouble>(),
+        ]))
+    );
+
+    let mut builder = ScheduleBuilder::new();
+    builder.add_process(IncreaseLevel {}).add_process(ReadWriteLevel {});
+    assert_eq!(
+        builder.build(&world).err(),
+        Some(ScheduleError::Conflict(std::any::type_name::<ReadWriteLevel>()))
+    );
+}
+
+#[cfg(feature = "parallel")]
+#[test]
+fn world_and_schedule_are_send_and_sync() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<World>();
+    assert_send_sync::<Schedule>();
 }
@@ -1,30 +1,37 @@
 #![allow(non_snake_case, unused_variables, unused_mut)]
 
 use crate::*;
-use std::cmp::max;
-use unordered_hash::UnorderedHasher;
+use std::cmp::{max, min};
 
 macro_rules! tuple {
 	($($T:ident),*) => {
 		impl<$($T: RefLike,)*> RefLike for ($($T,)*) {
-			type Borrowed = ($($T::Borrowed,)*);
-			fn borrow(&self) -> Self::Borrowed {
+			type Borrowed<'a> = ($($T::Borrowed<'a>,)*);
+			fn borrow(&self) -> Self::Borrowed<'_> {
 				let ($($T,)*) = self;
 				($($T.borrow(),)*)
 			}
 		}
 
 		impl<$($T: RefLikeMut,)*> RefLikeMut for ($($T,)*) {
-			type BorrowedMut = ($($T::BorrowedMut,)*);
-			fn borrow_mut(&self) -> Self::BorrowedMut {
+			type ItemMut = ($($T::ItemMut,)*);
+			type BorrowedMut<'a> = ($($T::BorrowedMut<'a>,)*);
+			fn borrow_mut(&self) -> Self::BorrowedMut<'_> {
 				let ($($T,)*) = self;
 				($($T.borrow_mut(),)*)
 			}
+			fn current_as_of(&self) -> Version {
+				let mut v = Version(u64::MAX);
+				let ($($T,)*) = self;
+				$(v = min(v, $T.current_as_of());)*
+				v
+			}
 		}
 
 		impl<$($T: BorrowedStorage,)*> BorrowedStorage for ($($T,)*) {
-			type Item = ($($T::Item,)*);
-			type Batch = ($($T::Batch,)*);
+			type Item<'a> = ($($T::Item<'a>,)*) where Self: 'a;
+			type Batch<'a> = ($($T::Batch<'a>,)*) where Self: 'a;
+			type EntityItem<'a> = ($($T::EntityItem<'a>,)*) where Self: 'a;
 			fn version(&self) -> Version {
 				let mut v = Version(0);
 				let ($($T,)*) = self;
@@ -32,18 +39,48 @@ macro_rules! tuple {
 				v
 			}
 
-			fn read(&self, index: usize) -> Option<Self::Item> {
+			fn read(&self, index: usize) -> Option<Self::Item<'_>> {
 				let ($($T,)*) = self;
 				$(let $T = $T.read(index)?;)*
 				Some(($($T,)*))
 			}
 
-			fn read_batch(&self) -> Self::Batch {
+			#[inline(always)]
+			fn read_entity(&self, index: usize) -> Self::EntityItem<'_> {
+				let ($($T,)*) = self;
+				($($T.read_entity(index),)*)
+			}
+
+			fn read_batch(&self) -> Self::Batch<'_> {
 				let ($($T,)*) = self;
 				($($T.read_batch(),)*)
 			}
 		}
 
+		impl<$($T: ReadableStorageOwned,)*> ReadableStorageOwned for ($($T,)*) {
+			type Owned = ($($T::Owned,)*);
+			fn read_owned(read: &Self::Read, index: usize) -> Option<Self::Owned> {
+				let ($($T,)*) = read;
+				$(let $T = $T::read_owned($T, index)?;)*
+				Some(($($T,)*))
+			}
+		}
+
+		impl<$($T: WritableStorage,)*> WritableStorage for ($($T,)*) {
+			type ReadMut = ($($T::ReadMut,)*);
+			#[inline(always)]
+			fn get_mut(world_storage: &Components, archetype: &Archetype) -> Option<Self::ReadMut> {
+				$(let $T = $T::get_mut(world_storage, archetype)?;)*
+				Some(($($T,)*))
+			}
+			fn add_write_types(types: &mut Vec<StorageType>) {
+				$($T::add_write_types(types);)*
+			}
+			fn prepare(archetype: &mut Archetype) {
+				$($T::prepare(archetype);)*
+			}
+		}
+
 		impl<$($T: ArchetypeFilter,)*> ArchetypeFilter for ($($T,)*) {
 			fn includes(&self, archetype: &Archetype) -> bool {
 				let ($($T,)*) = self;
@@ -51,6 +88,12 @@ macro_rules! tuple {
 			}
 		}
 
+		impl<$($T: Component,)*> ArchetypeFilter for AnyOf<($($T,)*)> {
+			fn includes(&self, archetype: &Archetype) -> bool {
+				false $(|| archetype.components().contains::<$T::Storage>())*
+			}
+		}
+
 		impl<$($T: ArchetypeInitializer,)*> ArchetypeInitializer for ($($T,)*) {
 			fn initialize(self, archetype: &mut Archetype) {
 				let ($($T,)*) = self;
@@ -64,9 +107,20 @@ macro_rules! tuple {
 				$($T.write(archetype, index);)*
 			}
 
-			fn add_archetype_requirements(&self, hasher: &mut UnorderedHasher) {
+			fn add_archetype_requirements(&self, requirements: &mut ArchetypeRequirements) {
 				let ($($T,)*) = self;
-				$($T.add_archetype_requirements(hasher);)*
+				$($T.add_archetype_requirements(requirements);)*
+			}
+		}
+
+		impl<$($T: EntityReader,)*> EntityReader for ($($T,)*) {
+			fn add_storage_types(types: &mut Vec<StorageType>) {
+				$($T::add_storage_types(types);)*
+			}
+
+			fn take(archetype: &Archetype, index: usize, top: usize) -> Option<Self> {
+				$(let $T = $T::take(archetype, index, top)?;)*
+				Some(($($T,)*))
 			}
 		}
 
@@ -77,6 +131,14 @@ macro_rules! tuple {
 				$(let $T = $T::get(world_storage, archetype_storage)?;)*
 				Some(($($T,)*))
 			}
+			fn add_read_types(types: &mut Vec<StorageType>) {
+				$($T::add_read_types(types);)*
+			}
+			#[inline(always)]
+			fn changed_since(read: &Self::Read, since: Version) -> bool {
+				let ($($T,)*) = read;
+				true $(&& $T::changed_since($T, since))*
+			}
 		}
 	};
 }
@@ -91,52 +153,93 @@ tuple!(T0, T1, T2, T3);
 
 impl BorrowedStorageMut for () {
     type ItemMut = ();
-    type BatchMut = ();
+    type BatchMut<'a> = ();
     fn write(&mut self, _index: usize, _item: Self::ItemMut) {
     }
-    fn write_batch(&mut self) -> Self::BatchMut {}
+    fn write_batch(&mut self) -> Self::BatchMut<'_> {}
+    fn set_version(&mut self, _version: Version) {}
+    fn current_as_of(&self) -> Version {
+        Version(u64::MAX)
+    }
+    fn set_current_as_of(&mut self, _version: Version) {}
 }
 
 impl<T0: BorrowedStorageMut> BorrowedStorageMut for (T0,) {
     type ItemMut = (T0::ItemMut,);
-    type BatchMut = (T0::BatchMut,);
+    type BatchMut<'a> = (T0::BatchMut<'a>,) where Self: 'a;
     fn write(&mut self, index: usize, item: Self::ItemMut) {
         self.0.write(index, item.0);
     }
-    fn write_batch(&mut self) -> Self::BatchMut {
+    fn write_batch(&mut self) -> Self::BatchMut<'_> {
         (self.0.write_batch(),)
     }
+    fn set_version(&mut self, version: Version) {
+        self.0.set_version(version);
+    }
+    fn current_as_of(&self) -> Version {
+        self.0.current_as_of()
+    }
+    fn set_current_as_of(&mut self, version: Version) {
+        self.0.set_current_as_of(version);
+    }
 }
 
 impl<T0: BorrowedStorageMut, T1: BorrowedStorageMut> BorrowedStorageMut for (T0, T1) {
     type ItemMut = (T0::ItemMut, T1::ItemMut);
-    type BatchMut = (T0::BatchMut, T1::BatchMut);
+    type BatchMut<'a> = (T0::BatchMut<'a>, T1::BatchMut<'a>) where Self: 'a;
     fn write(&mut self, index: usize, item: Self::ItemMut) {
         self.0.write(index, item.0);
         self.1.write(index, item.1);
     }
-    fn write_batch(&mut self) -> Self::BatchMut {
+    fn write_batch(&mut self) -> Self::BatchMut<'_> {
         (self.0.write_batch(), self.1.write_batch())
     }
+    fn set_version(&mut self, version: Version) {
+        self.0.set_version(version);
+        self.1.set_version(version);
+    }
+    fn current_as_of(&self) -> Version {
+        self.0.current_as_of().min(self.1.current_as_of())
+    }
+    fn set_current_as_of(&mut self, version: Version) {
+        self.0.set_current_as_of(version);
+        self.1.set_current_as_of(version);
+    }
 }
 
 impl<T0: BorrowedStorageMut, T1: BorrowedStorageMut, T2: BorrowedStorageMut> BorrowedStorageMut
     for (T0, T1, T2)
 {
     type ItemMut = (T0::ItemMut, T1::ItemMut, T2::ItemMut);
-    type BatchMut = (T0::BatchMut, T1::BatchMut, T2::BatchMut);
+    type BatchMut<'a> = (T0::BatchMut<'a>, T1::BatchMut<'a>, T2::BatchMut<'a>) where Self: 'a;
     fn write(&mut self, index: usize, item: Self::ItemMut) {
         self.0.write(index, item.0);
         self.1.write(index, item.1);
         self.2.write(index, item.2);
     }
-    fn write_batch(&mut self) -> Self::BatchMut {
+    fn write_batch(&mut self) -> Self::BatchMut<'_> {
         (
             self.0.write_batch(),
             self.1.write_batch(),
             self.2.write_batch(),
         )
     }
+    fn set_version(&mut self, version: Version) {
+        self.0.set_version(version);
+        self.1.set_version(version);
+        self.2.set_version(version);
+    }
+    fn current_as_of(&self) -> Version {
+        self.0
+            .current_as_of()
+            .min(self.1.current_as_of())
+            .min(self.2.current_as_of())
+    }
+    fn set_current_as_of(&mut self, version: Version) {
+        self.0.set_current_as_of(version);
+        self.1.set_current_as_of(version);
+        self.2.set_current_as_of(version);
+    }
 }
 
 impl<
@@ -147,14 +250,14 @@ impl<
     > BorrowedStorageMut for (T0, T1, T2, T3)
 {
     type ItemMut = (T0::ItemMut, T1::ItemMut, T2::ItemMut, T3::ItemMut);
-    type BatchMut = (T0::BatchMut, T1::BatchMut, T2::BatchMut, T3::BatchMut);
+    type BatchMut<'a> = (T0::BatchMut<'a>, T1::BatchMut<'a>, T2::BatchMut<'a>, T3::BatchMut<'a>) where Self: 'a;
     fn write(&mut self, index: usize, item: Self::ItemMut) {
         self.0.write(index, item.0);
         self.1.write(index, item.1);
         self.2.write(index, item.2);
         self.3.write(index, item.3);
     }
-    fn write_batch(&mut self) -> Self::BatchMut {
+    fn write_batch(&mut self) -> Self::BatchMut<'_> {
         (
             self.0.write_batch(),
             self.1.write_batch(),
@@ -162,5 +265,24 @@ impl<
             self.3.write_batch(),
         )
     }
+    fn set_version(&mut self, version: Version) {
+        self.0.set_version(version);
+        self.1.set_version(version);
+        self.2.set_version(version);
+        self.3.set_version(version);
+    }
+    fn current_as_of(&self) -> Version {
+        self.0
+            .current_as_of()
+            .min(self.1.current_as_of())
+            .min(self.2.current_as_of())
+            .min(self.3.current_as_of())
+    }
+    fn set_current_as_of(&mut self, version: Version) {
+        self.0.set_current_as_of(version);
+        self.1.set_current_as_of(version);
+        self.2.set_current_as_of(version);
+        self.3.set_current_as_of(version);
+    }
 }
 
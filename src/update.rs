@@ -1,10 +1,12 @@
 use crate::*;
 
 pub trait Update {
+    /// Storages which are modified by the update should be stamped with the version.
     fn execute<'a>(
         &self,
         world_storage: &Components,
         world: impl Iterator<Item = &'a mut Option<Archetype>>,
+        version: Version,
     );
 }
 
@@ -12,8 +14,12 @@ pub trait CullArchetypes {
     type Reads: ReadableStorage;
     fn should_cull(
         &self,
-        data: <<<<Self as CullArchetypes>::Reads as ReadableStorage>::Read as RefLike>::Borrowed as BorrowedStorage>::Batch,
+        data: <<<<Self as CullArchetypes>::Reads as ReadableStorage>::Read as RefLike>::Borrowed<'_> as BorrowedStorage>::Batch<'_>,
     ) -> bool;
+
+    /// Archetypes not included by the filter are never culled. Includes every archetype unless overridden.
+    #[inline(always)]
+    fn filter(&self) -> impl ArchetypeFilter {}
 }
 
 impl<T: CullArchetypes> Update for T {
@@ -21,12 +27,15 @@ impl<T: CullArchetypes> Update for T {
         &self,
         world_storage: &Components,
         archetypes: impl Iterator<Item = &'b mut Option<Archetype>>,
+        _version: Version,
     ) {
+        let filter = self.filter();
         for archetype in archetypes {
             let mut f = false;
             {
                 if let Some(storage) = archetype
                     .as_ref()
+                    .filter(|a| filter.includes(a))
                     .and_then(|a| T::Reads::get(world_storage, a.components()))
                 {
                     let borrow = storage.borrow();
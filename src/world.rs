@@ -1,6 +1,8 @@
 use super::*;
+use std::any::TypeId;
+use crate::sync::{Parallel, Ref, RefMut};
 use std::collections::HashMap;
-use unordered_hash::UnorderedHasher;
+use crate::sync::Shared;
 
 #[derive(Eq, PartialEq, Copy, Debug, Clone, Hash)]
 pub struct UniqueId(pub u128);
@@ -17,7 +19,7 @@ struct EntitySlot {
 
 // TODO: Rather than hiding methods on the world, wrap it in some kind of processor that has the schedule_query, schedule_update, etc methods.
 
-// TODO: Update / Version rules
+// Update / Version rules
 // 0. The World starts at Version(0)
 // 1. An update always runs.
 // 2. When an update runs, the world version number is increased by 1, and all components modified by add or mutate (but not delete) have their version set to this.
@@ -28,8 +30,18 @@ struct EntitySlot {
 
 pub struct World {
     archetypes: Vec<Option<Archetype>>,
+    // Indices into archetypes by key. An archetype is split into more than one
+    // chunk when it has more than CAPACITY entities.
+    archetype_indices: HashMap<ArchetypeKey, Vec<usize>>,
+    // Indices of archetypes which are None, to be reused
+    free_archetypes: Vec<usize>,
     entities: HashMap<UniqueId, EntitySlot>,
     globals: Components,
+    version: Version,
+    // Processes which produce derived components, in the order they were registered.
+    derived: Vec<(TypeId, Box<dyn Derive>)>,
+    // Queries, updates and processes to be run by World::run, in the order they were scheduled.
+    scheduled: ScheduleBuilder,
 }
 
 impl Default for World {
@@ -40,10 +52,36 @@ impl Default for World {
 
 impl World {
     pub fn new() -> Self {
+        let mut globals = Components::new();
+        globals.add(Global::new(Commands::new()));
         Self {
             archetypes: Vec::new(),
+            archetype_indices: HashMap::new(),
+            free_archetypes: Vec::new(),
             entities: HashMap::new(),
-            globals: Components::new(),
+            globals,
+            version: Version(0),
+            derived: Vec::new(),
+            scheduled: ScheduleBuilder::new(),
+        }
+    }
+
+    /// The version of the most recent change to the world.
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    /// Increases the version for a change to the world, returning the version to stamp modified storages with.
+    fn next_version(&mut self) -> Version {
+        self.version = Version(self.version.0 + 1);
+        self.version
+    }
+
+    /// Writing a storage which is also read or written elsewhere by the same process would otherwise
+    /// fail on the mutable borrow part way through the process, so this is checked before borrowing.
+    fn check_access<R: ReadableStorage, W: WritableStorage>() {
+        if let Err(error) = Access::of::<R, W>().check() {
+            panic!("{}", error);
         }
     }
 
@@ -54,63 +92,183 @@ impl World {
             .sum()
     }
 
-    // TODO: Make this ReadableStorage, to support eg: tuples
-    pub fn read_component<T: ReadableStorage>(
-        &self,
-        entity: &UniqueId,
-    ) -> Option<<<<T as ReadableStorage>::Read as RefLike>::Borrowed as BorrowedStorage>::Item>
+    /// Borrows the component of the entity without cloning it. The world can not be changed while
+    /// the component is borrowed.
+    pub fn component<T: Component>(&self, entity: &UniqueId) -> Option<Ref<'_, T>>
     where
-        <<<T as ReadableStorage>::Read as RefLike>::Borrowed as BorrowedStorage>::Item: Copy,
+        T::Storage: BorrowFromComponentStorage<Component = T>,
     {
         let slot = self.entities.get(entity)?;
-        let archetype = &self.archetypes[slot.archetype_index].as_ref();
-        let storage = T::get(&self.globals, &archetype.unwrap().components())?;
-        storage.borrow().read(slot.entity_index)
+        let archetype = self.archetypes[slot.archetype_index].as_ref().unwrap();
+        self.update_derived(archetype);
+        let storage = archetype.components().any.get(&TypeId::of::<T::Storage>())?;
+        storage.downcast_ref::<T::Storage>()?.borrow_component(slot.entity_index)
+    }
+
+    /// Clones the components of the entity, which may be more than one, eg: (Level, Kind).
+    /// See also World::component
+    pub fn read_component<T: ReadableStorageOwned>(&self, entity: &UniqueId) -> Option<T::Owned> {
+        self.try_read_component::<T>(entity).ok()
+    }
+
+    pub fn try_read_component<T: ReadableStorageOwned>(&self, entity: &UniqueId) -> Result<T::Owned, Error> {
+        let slot = self.entities.get(entity).ok_or(Error::EntityNotFound(*entity))?;
+        let archetype = self.archetypes[slot.archetype_index].as_ref().unwrap();
+        self.update_derived(archetype);
+        let not_found = || Error::ComponentNotFound {
+            entity: *entity,
+            component: std::any::type_name::<T>(),
+        };
+        let storage = T::get(&self.globals, archetype.components()).ok_or_else(not_found)?;
+        T::read_owned(&storage, slot.entity_index).ok_or_else(not_found)
+    }
+
+    /// The version at which the components of the entity were last modified.
+    pub fn read_version<T: ReadableStorage>(&self, entity: &UniqueId) -> Option<Version> {
+        let slot = self.entities.get(entity)?;
+        let archetype = self.archetypes[slot.archetype_index].as_ref().unwrap();
+        self.update_derived(archetype);
+        let storage = T::get(&self.globals, archetype.components())?;
+        let borrow = storage.borrow();
+        borrow.read(slot.entity_index)?;
+        Some(borrow.version())
     }
 
     fn add_entity_inner<T: EntityWriter + ArchetypeInitializer>(
         &mut self,
+        key: ArchetypeKey,
         entity: T,
     ) -> EntitySlot {
-		let mut hasher = UnorderedHasher::new();
-		entity.add_archetype_requirements(&mut hasher);
-		let requirements = hasher.finish();
-
-        // First try writing it to a matching archetype
-        for (slot, archetype_index) in self.archetypes.iter_mut().zip(0..std::usize::MAX) {
-            if let Some(archetype) = slot {
-
-                if archetype.get_requirements() == requirements {
-                    let entity_index = archetype.entity_write_slot();
-                    entity.write(archetype, entity_index);
-                    return EntitySlot {
-                        archetype_index,
-                        entity_index,
-                    };
+        let version = self.next_version();
+        let (archetype_index, created) = self.find_or_insert_archetype(key);
+        let archetype = self.archetypes[archetype_index].as_mut().unwrap();
+        let entity_index = archetype.entity_write_slot();
+        if created {
+            entity.initialize(archetype);
+        } else {
+            entity.write(archetype, entity_index);
+        }
+        archetype.set_version(version);
+        EntitySlot {
+            archetype_index,
+            entity_index,
+        }
+    }
+
+    /// Returns the index of an archetype with the key that has room for an entity, and whether it was newly created.
+    fn find_or_insert_archetype(&mut self, key: ArchetypeKey) -> (usize, bool) {
+        if let Some(archetype_indices) = self.archetype_indices.get(&key) {
+            // The most recently created chunk is the most likely to have room.
+            for &archetype_index in archetype_indices.iter().rev() {
+                if !self.archetypes[archetype_index].as_ref().unwrap().is_full() {
+                    return (archetype_index, false);
                 }
             }
         }
-        // If no archetype matches, create a new one.
-        let mut archetype = Archetype::new(requirements);
-        let entity_index = archetype.entity_write_slot();
-        entity.initialize(&mut archetype);
-
-        // Find an empty slot to place the archetype
-        for (slot, archetype_index) in self.archetypes.iter_mut().zip(0..std::usize::MAX) {
-            if slot.is_none() {
-                *slot = Some(archetype);
-                return EntitySlot {
-                    archetype_index,
-                    entity_index,
-                };
+
+        // If no archetype matches or they are all full, create a new one in a vacated slot if there is one.
+        let archetype = Archetype::new(key.clone());
+        let archetype_index = match self.free_archetypes.pop() {
+            Some(archetype_index) => {
+                debug_assert!(self.archetypes[archetype_index].is_none());
+                self.archetypes[archetype_index] = Some(archetype);
+                archetype_index
+            }
+            None => {
+                self.archetypes.push(Some(archetype));
+                self.archetypes.len() - 1
             }
+        };
+        self.archetype_indices.entry(key).or_default().push(archetype_index);
+        (archetype_index, true)
+    }
+
+    /// Removes the archetype at archetype_index, which must not have any entities.
+    fn free_archetype(&mut self, archetype_index: usize) {
+        let archetype = self.archetypes[archetype_index].take().unwrap();
+        debug_assert!(archetype.num_entities() == 0);
+        let archetype_indices = self.archetype_indices.get_mut(archetype.get_key()).unwrap();
+        archetype_indices.retain(|&i| i != archetype_index);
+        if archetype_indices.is_empty() {
+            self.archetype_indices.remove(archetype.get_key());
         }
+        self.free_archetypes.push(archetype_index);
+    }
 
-        // Create a new slot if none were found.
-        self.archetypes.push(Some(archetype));
-        EntitySlot {
-            archetype_index: self.archetypes.len() - 1,
-            entity_index,
+    /// The UniqueId column of each live archetype, so that the entities of any archetypes an update
+    /// removes can be found afterwards.
+    fn live_archetypes(&self) -> Vec<(usize, Shared<PerEntity<UniqueId>>)> {
+        self.archetypes
+            .iter()
+            .enumerate()
+            .filter_map(|(archetype_index, archetype)| {
+                let unique_ids = archetype.as_ref()?.get_storage::<PerEntity<UniqueId>>()?;
+                Some((archetype_index, unique_ids))
+            })
+            .collect()
+    }
+
+    /// Brings the indices up to date with any of the previously live archetypes which were removed by an update.
+    fn sync_removed_archetypes(&mut self, live: Vec<(usize, Shared<PerEntity<UniqueId>>)>) {
+        let mut removed = false;
+        for (archetype_index, unique_ids) in live {
+            if self.archetypes[archetype_index].is_some() {
+                continue;
+            }
+            removed = true;
+            for unique_id in unique_ids.borrow().read_batch() {
+                self.entities.remove(unique_id);
+            }
+        }
+        if !removed {
+            return;
+        }
+
+        let archetypes = &self.archetypes;
+        let free_archetypes = &mut self.free_archetypes;
+        self.archetype_indices.retain(|_, archetype_indices| {
+            archetype_indices.retain(|&archetype_index| {
+                let keep = archetypes[archetype_index].is_some();
+                if !keep {
+                    free_archetypes.push(archetype_index);
+                }
+                keep
+            });
+            !archetype_indices.is_empty()
+        });
+    }
+
+    fn archetype_pair_mut(&mut self, a: usize, b: usize) -> (&mut Archetype, &mut Archetype) {
+        assert_ne!(a, b);
+        let (a, b) = if a < b {
+            let (low, high) = self.archetypes.split_at_mut(b);
+            (&mut low[a], &mut high[0])
+        } else {
+            let (low, high) = self.archetypes.split_at_mut(a);
+            (&mut high[0], &mut low[b])
+        };
+        (a.as_mut().unwrap(), b.as_mut().unwrap())
+    }
+
+    /// To be called after the entity at slot has been removed from it's archetype. Drops the archetype
+    /// if it is empty, or else fixes the slot of the entity which was swapped into the vacated index.
+    fn entity_vacated(&mut self, slot: EntitySlot) {
+        match &self.archetypes[slot.archetype_index] {
+            Some(inner) => {
+                if inner.num_entities() == 0 {
+                    self.free_archetype(slot.archetype_index);
+                } else if slot.entity_index < inner.num_entities() {
+                    // The UniqueId column doubles as the reverse index from entity index to entity.
+                    let moved = *inner
+                        .get_storage::<PerEntity<UniqueId>>()
+                        .unwrap()
+                        .borrow()
+                        .read(slot.entity_index)
+                        .unwrap();
+                    self.entities.insert(moved, slot);
+                }
+            }
+            None => unreachable!(),
         }
     }
 
@@ -119,59 +277,486 @@ impl World {
         unique_id: UniqueId,
         entity: T,
     ) {
-        // No duplicate entries
-        assert!(!self.entities.contains_key(&unique_id));
+        if let Err(error) = self.try_add_entity(unique_id, entity) {
+            panic!("{}", error);
+        }
+    }
+
+    /// Fails without changing the world if the UniqueId is taken, or a component is given twice.
+    pub fn try_add_entity<T: EntityWriter + ArchetypeInitializer>(
+        &mut self,
+        unique_id: UniqueId,
+        entity: T,
+    ) -> Result<(), Error> {
+        if self.entities.contains_key(&unique_id) {
+            return Err(Error::DuplicateEntity(unique_id));
+        }
         // Extend entity with unique_id component
         let entity = (unique_id, entity);
-        let slot = self.add_entity_inner(entity);
+        let mut requirements = ArchetypeRequirements::new();
+        entity.add_archetype_requirements(&mut requirements);
+        if let Some(component) = requirements.duplicate() {
+            return Err(Error::DuplicateComponent {
+                entity: unique_id,
+                component,
+            });
+        }
+        let slot = self.add_entity_inner(requirements.finish(), entity);
         self.entities.insert(unique_id, slot);
+        Ok(())
     }
 
+	/// Removing an entity which does not exist panics in debug builds, and is ignored otherwise.
 	pub fn remove_entity(&mut self, unique_id: UniqueId) {
-		let slot = self.entities.remove(&unique_id);
-		match slot {
-			Some(slot) => {
-				let archetype = &mut self.archetypes[slot.archetype_index];
-				match archetype {
-					Some(inner) => {
-						inner.remove_entity(slot.entity_index);
-						if inner.num_entities() == 0 {
-							*archetype = None;
-						}
-					},
-					None => unreachable!(),
-				}
-			},
-			None => {
-				#[cfg(debug_assertions)]
-				unreachable!();
-			}
+		let result = self.try_remove_entity(unique_id);
+		debug_assert!(result.is_ok(), "{}", result.unwrap_err());
+	}
+
+	pub fn try_remove_entity(&mut self, unique_id: UniqueId) -> Result<(), Error> {
+		let slot = self.entities.remove(&unique_id).ok_or(Error::EntityNotFound(unique_id))?;
+		match &mut self.archetypes[slot.archetype_index] {
+			Some(inner) => inner.remove_entity(slot.entity_index),
+			None => unreachable!(),
 		}
+		self.entity_vacated(slot);
+		Ok(())
 	}
 
+    /// Removes the entity from the world, returning it's components. T must name each of the
+    /// entity's components (other than it's UniqueId) exactly once, or else the world is left unchanged.
+    pub fn take_entity<T: EntityReader>(&mut self, unique_id: UniqueId) -> Result<T, Error> {
+        let slot = *self.entities.get(&unique_id).ok_or(Error::EntityNotFound(unique_id))?;
+        self.update_derived(self.archetypes[slot.archetype_index].as_ref().unwrap());
+        let archetype = self.archetypes[slot.archetype_index].as_mut().unwrap();
+
+        let mut requested = Vec::new();
+        UniqueId::add_storage_types(&mut requested);
+        T::add_storage_types(&mut requested);
+        requested.sort();
+        let found = archetype.storage_types(slot.entity_index);
+        if requested != found {
+            // Those requested twice, requested but not found, or found but not requested.
+            let components = requested
+                .windows(2)
+                .filter(|pair| pair[0] == pair[1])
+                .map(|pair| pair[0].name)
+                .chain(requested.iter().filter(|t| !found.contains(t)).map(|t| t.name))
+                .chain(found.iter().filter(|t| !requested.contains(t)).map(|t| t.name))
+                .collect();
+            return Err(Error::ComponentsMismatch {
+                entity: unique_id,
+                components,
+            });
+        }
+
+        let entity = archetype.take_entity(slot.entity_index).unwrap();
+        self.entities.remove(&unique_id);
+        self.entity_vacated(slot);
+        Ok(entity)
+    }
+
+    /// Adds the component to an existing entity, replacing any previous value. If this changes the
+    /// entity's archetype, the entity is moved into the matching archetype.
+    pub fn insert_component<T: Component + EntityWriter + ArchetypeInitializer>(
+        &mut self,
+        unique_id: UniqueId,
+        component: T,
+    ) where
+        T::Storage: EntityReaderFromComponentStorage<Component = T>,
+    {
+        if let Err(error) = self.try_insert_component(unique_id, component) {
+            panic!("{}", error);
+        }
+    }
+
+    pub fn try_insert_component<T: Component + EntityWriter + ArchetypeInitializer>(
+        &mut self,
+        unique_id: UniqueId,
+        component: T,
+    ) -> Result<(), Error>
+    where
+        T::Storage: EntityReaderFromComponentStorage<Component = T>,
+    {
+        if TypeId::of::<T>() == TypeId::of::<UniqueId>() {
+            return Err(Error::UniqueIdReplaced(unique_id));
+        }
+        let slot = *self.entities.get(&unique_id).ok_or(Error::EntityNotFound(unique_id))?;
+        let exclude = TypeId::of::<T::Storage>();
+        let version = self.next_version();
+
+        let source = self.archetypes[slot.archetype_index].as_mut().unwrap();
+        let mut requirements = ArchetypeRequirements::new();
+        source.add_archetype_requirements(&mut requirements, exclude);
+        component.add_archetype_requirements(&mut requirements);
+        let key = requirements.finish();
+
+        if key == *source.get_key() {
+            // Overwriting a PerEntity value, or a Sparse value which does not affect the archetype.
+            component.write(source, slot.entity_index);
+            source.get_storage::<T::Storage>().unwrap().set_version(version);
+            return Ok(());
+        }
+
+        let (destination_index, created) = self.find_or_insert_archetype(key);
+        let (source, destination) = self.archetype_pair_mut(slot.archetype_index, destination_index);
+        let entity_index = destination.entity_write_slot();
+        let top = source.move_entity(slot.entity_index, destination, entity_index, exclude);
+        T::Storage::take(source, slot.entity_index, top);
+        if created {
+            component.initialize(destination);
+        } else {
+            component.write(destination, entity_index);
+        }
+        destination.set_version(version);
+
+        self.entities.insert(unique_id, EntitySlot { archetype_index: destination_index, entity_index });
+        self.entity_vacated(slot);
+        Ok(())
+    }
+
+    /// Removes the component from an existing entity, moving the entity into the matching archetype.
+    /// Returns None if the entity does not exist or does not have the component.
+    pub fn remove_component<T: Component>(&mut self, unique_id: UniqueId) -> Option<T>
+    where
+        T::Storage: EntityReaderFromComponentStorage<Component = T>,
+    {
+        assert!(TypeId::of::<T>() != TypeId::of::<UniqueId>(), "The UniqueId of an entity cannot be removed");
+        let slot = *self.entities.get(&unique_id)?;
+        let exclude = TypeId::of::<T::Storage>();
+
+        self.update_derived(self.archetypes[slot.archetype_index].as_ref().unwrap());
+        let source = self.archetypes[slot.archetype_index].as_mut().unwrap();
+        T::get(&self.globals, source.components())?.borrow().read(slot.entity_index)?;
+        let mut requirements = ArchetypeRequirements::new();
+        source.add_archetype_requirements(&mut requirements, exclude);
+        let key = requirements.finish();
+
+        if key == *source.get_key() {
+            // Only Sparse values may be removed without changing the archetype. Passing the
+            // same index as top leaves the other entities alone.
+            return T::Storage::take(source, slot.entity_index, slot.entity_index);
+        }
+
+        // Removal does not change the version, but adding the entity to the destination does.
+        let version = self.next_version();
+        let (destination_index, _) = self.find_or_insert_archetype(key);
+        let (source, destination) = self.archetype_pair_mut(slot.archetype_index, destination_index);
+        let entity_index = destination.entity_write_slot();
+        let top = source.move_entity(slot.entity_index, destination, entity_index, exclude);
+        destination.set_version(version);
+        let component = T::Storage::take(source, slot.entity_index, top);
+
+        self.entities.insert(unique_id, EntitySlot { archetype_index: destination_index, entity_index });
+        self.entity_vacated(slot);
+        component
+    }
+
+    /// Adds a value which is shared by all entities, and can be read as Global<T>. Returns the
+    /// previous value if there was one.
+    pub fn insert_global<T: Storable>(&mut self, value: T) -> Option<T> {
+        let previous = self.remove_global::<T>();
+        let version = self.next_version();
+        self.globals.add(Global::with_version(value, version));
+        previous
+    }
+
+    pub fn global<T: Storable>(&self) -> Option<Ref<'_, T>> {
+        let storage = self.globals.any.get(&TypeId::of::<Global<T>>())?;
+        storage.downcast_ref::<Global<T>>().map(|g| g.get())
+    }
+
+    /// Mutably borrows the global, which stamps it as modified.
+    pub fn global_mut<T: Storable>(&mut self) -> Option<RefMut<'_, T>> {
+        let type_id = TypeId::of::<Global<T>>();
+        if !self.globals.any.contains_key(&type_id) {
+            return None;
+        }
+        let version = self.next_version();
+        let storage = &self.globals.any[&type_id];
+        storage.set_version(version);
+        storage.downcast_ref::<Global<T>>().map(|g| g.get_mut())
+    }
+
+    pub fn remove_global<T: Storable>(&mut self) -> Option<T> {
+        let storage = self.globals.remove::<Global<T>>()?;
+        match Shared::try_unwrap(storage) {
+            Ok(storage) => Some(storage.into_inner()),
+            Err(_) => panic!("Removed a global while it is in use"),
+        }
+    }
+
+    /// Registers the process as the producer of the derived component T. Before T is read, the process
+    /// is run for each archetype where the Reads of the process have changed since T was last derived.
+    /// A derived component which reads another should be registered after it.
+    /// PerEntity derived components must be added with each entity, eg: with a default value.
+    pub fn register_derived<T: Component, P: Process<Writes = T> + Storable>(&mut self, process: P) {
+        let type_id = TypeId::of::<T>();
+        assert!(
+            self.derived.iter().all(|(t, _)| *t != type_id),
+            "Registered a derived component twice"
+        );
+        // Writes is only known to be T here, which hides that it is a WritableStorage.
+        fn check_process_access<P: Process>() {
+            World::check_access::<P::Reads, P::Writes>();
+        }
+        check_process_access::<P>();
+        self.derived.push((type_id, Box::new(Derived(process))));
+    }
+
+    /// Adds the reads of the producers of any derived components which the access reads.
+    pub(crate) fn with_derived_reads(&self, mut access: Access) -> Access {
+        // A derived component may read those registered before it, so they are added last.
+        for (_, derived) in self.derived.iter().rev() {
+            access.add_derived_reads(&derived.access());
+        }
+        access
+    }
+
+    fn update_derived(&self, archetype: &Archetype) {
+        for (_, derived) in self.derived.iter() {
+            derived.update(&self.globals, archetype, self.version);
+        }
+    }
+
+    pub(crate) fn update_all_derived(&self) {
+        if self.derived.is_empty() {
+            return;
+        }
+        for archetype in self.archetypes.iter().flatten() {
+            self.update_derived(archetype);
+        }
+    }
+
     pub fn execute_query<T: Query>(&self, query: &T) -> T::Output {
-        let query_data = QueryData::new(&self.globals, &self.archetypes);
-        query.execute(query_data)
+        self.update_all_derived();
+        self.run_query(query)
+    }
+
+    /// Executes the query without bringing derived components up to date.
+    pub(crate) fn run_query<T: Query>(&self, query: &T) -> T::Output {
+        let filter = query.filter();
+        with_query_data(&self.globals, &self.archetypes, &filter, query.changed_since(), |data| {
+            query.execute(data)
+        })
     }
 
     pub fn execute_update<T: Update>(&mut self, update: &T) {
-        update.execute(&self.globals, self.archetypes.iter_mut())
-    }
-
-    pub fn execute_process<T: Process>(&mut self, process: &T) {
-        for archetype in self.archetypes.iter_mut() {
-            if let Some(archetype) = archetype {
-                // TODO: Just add an archetype iterator.
-                if let Some(read) = T::Reads::get(&self.globals, archetype.components()) {
-                    if let Some(write) = T::Writes::get_mut(archetype) {
-                        let read_borrow = read.borrow();
-                        let mut write_borrow = write.borrow_mut();
-                        let read_batch = read_borrow.read_batch();
-                        let write_batch = write_borrow.write_batch();
-                        process.execute(read_batch, write_batch);
+        self.update_all_derived();
+        let version = self.next_version();
+        let live = self.live_archetypes();
+        update.execute(&self.globals, self.archetypes.iter_mut(), version);
+        self.sync_removed_archetypes(live);
+        self.apply_commands();
+    }
+
+    /// With the parallel feature, the archetypes are processed on the rayon thread pool.
+    pub fn execute_process<T: Process + Parallel>(&mut self, process: &T) {
+        let version = self.prepare_process(process);
+        self.run_process(process, version);
+        self.apply_commands();
+    }
+
+    /// Like execute_process, but fails instead of panicking when the process reads a component
+    /// that it writes, or writes a component twice.
+    pub fn try_execute_process<T: Process + Parallel>(&mut self, process: &T) -> Result<(), Error> {
+        Access::of::<T::Reads, T::Writes>().check()?;
+        self.execute_process(process);
+        Ok(())
+    }
+
+    /// Does everything for execute_process which needs &mut self, returning the version to write.
+    pub(crate) fn prepare_process<T: Process>(&mut self, process: &T) -> Version {
+        Self::check_access::<T::Reads, T::Writes>();
+        // Derived components are brought up to date before the version changes, so that
+        // they are out of date again if the process writes to their inputs.
+        self.update_all_derived();
+        let filter = process.filter();
+        let version = self.next_version();
+        // Only archetypes which the process runs on are prepared, so that writing a Sparse
+        // component does not add it's storage to every archetype.
+        for archetype in self.archetypes.iter_mut().flatten() {
+            if filter.includes(archetype) && T::Reads::get(&self.globals, archetype.components()).is_some() {
+                T::Writes::prepare(archetype);
+            }
+        }
+        version
+    }
+
+    pub(crate) fn run_process<T: Process + Parallel>(&self, process: &T, version: Version) {
+        let filter = process.filter();
+        let since = process.changed_since();
+        let storages = |globals: &Components, archetype: &Archetype| {
+            let read = T::Reads::get(globals, archetype.components())?;
+            if !T::Reads::changed_since(&read, since) {
+                return None;
+            }
+            let write = T::Writes::get_mut(globals, archetype)?;
+            Some((read, write))
+        };
+        let execute = |(read, write): (<T::Reads as ReadableStorage>::Read, <T::Writes as WritableStorage>::ReadMut)| {
+            let read_borrow = read.borrow();
+            let mut write_borrow = write.borrow_mut();
+            let read_batch = read_borrow.read_batch();
+            write_borrow.set_version(version);
+            let write_batch = write_borrow.write_batch();
+            process.execute(read_batch, write_batch);
+        };
+        let archetypes = self.archetypes.iter().flatten().filter(|archetype| filter.includes(archetype));
+        #[cfg(not(feature = "parallel"))]
+        archetypes
+            .filter_map(|archetype| storages(&self.globals, archetype))
+            .for_each(execute);
+        #[cfg(feature = "parallel")]
+        {
+            use rayon::prelude::*;
+            let mut writes = Vec::new();
+            T::Writes::add_write_types(&mut writes);
+            let commands_type = StorageType::of::<Global<Commands>>();
+            // Other Globals are shared by every archetype, so they are written by one archetype at a time.
+            if writes.iter().any(|write| *write != commands_type && self.globals.any.contains_key(&write.id)) {
+                archetypes
+                    .filter_map(|archetype| storages(&self.globals, archetype))
+                    .for_each(execute);
+                return;
+            }
+            let commands = match self.globals.get_storage::<Global<Commands>>() {
+                Some(commands) if writes.contains(&commands_type) => commands,
+                _ => {
+                    archetypes
+                        .filter_map(|archetype| storages(&self.globals, archetype))
+                        .collect::<Vec<_>>()
+                        .into_par_iter()
+                        .for_each(execute);
+                    return;
+                }
+            };
+            // Each archetype queues commands of it's own, which are then queued in archetype order
+            // as they are without the parallel feature, rather than in the order the threads finish.
+            let queued: Vec<Option<Commands>> = archetypes
+                .collect::<Vec<_>>()
+                .into_par_iter()
+                .map(|archetype| {
+                    let mut globals = Components {
+                        any: self.globals.any.clone(),
+                    };
+                    globals.remove::<Global<Commands>>();
+                    globals.add(Global::new(Commands::new()));
+                    execute(storages(&globals, archetype)?);
+                    match Shared::try_unwrap(globals.remove::<Global<Commands>>().unwrap()) {
+                        Ok(storage) => Some(storage.into_inner()),
+                        Err(_) => unreachable!(),
                     }
+                })
+                .collect();
+            if queued.iter().any(Option::is_some) {
+                commands.set_version(version);
+            }
+            let mut commands = commands.get_mut();
+            for queued in queued.into_iter().flatten() {
+                commands.append(queued);
+            }
+        }
+    }
+
+    /// Executes the process for each entity of the archetypes which have it's Reads and Writes,
+    /// writing the result back through BorrowedStorageMut::write.
+    pub fn execute_process_simple<T: ProcessSimple + Parallel>(&mut self, process: &T) {
+        let version = self.prepare_process_simple::<T>();
+        self.run_process_simple(process, version);
+        self.apply_commands();
+    }
+
+    pub(crate) fn prepare_process_simple<T: ProcessSimple>(&mut self) -> Version {
+        Self::check_access::<T::Reads, T::Writes>();
+        self.update_all_derived();
+        let version = self.next_version();
+        for archetype in self.archetypes.iter_mut().flatten() {
+            if T::Reads::get(&self.globals, archetype.components()).is_some() {
+                T::Writes::prepare(archetype);
+            }
+        }
+        version
+    }
+
+    pub(crate) fn run_process_simple<T: ProcessSimple + Parallel>(&self, process: &T, version: Version) {
+        let storages = self.archetypes.iter().flatten().filter_map(|archetype| {
+            let read = T::Reads::get(&self.globals, archetype.components())?;
+            let write = T::Writes::get_mut(&self.globals, archetype)?;
+            Some((read, write, archetype.num_entities()))
+        });
+        let execute = |(read, write, len): (<T::Reads as ReadableStorage>::Read, <T::Writes as WritableStorage>::ReadMut, usize)| {
+            let read_borrow = read.borrow();
+            let mut write_borrow = write.borrow_mut();
+            write_borrow.set_version(version);
+            for index in 0..len {
+                if let Some(item) = read_borrow.read(index) {
+                    write_borrow.write(index, process.execute(item));
                 }
             }
+        };
+        #[cfg(not(feature = "parallel"))]
+        storages.for_each(execute);
+        #[cfg(feature = "parallel")]
+        {
+            use rayon::prelude::*;
+            let mut writes = Vec::new();
+            T::Writes::add_write_types(&mut writes);
+            // A Global is shared by every archetype, so it is written by one archetype at a time.
+            if writes.iter().any(|write| self.globals.any.contains_key(&write.id)) {
+                storages.for_each(execute);
+            } else {
+                storages.collect::<Vec<_>>().into_par_iter().for_each(execute);
+            }
+        }
+    }
+
+    /// Queues the query to be run by World::run. The handle is a future of the output.
+    pub fn schedule_query<T: Query + Storable>(&mut self, query: T) -> QueryHandle<T::Output>
+    where
+        T::Output: Storable,
+    {
+        let handle = QueryHandle::new();
+        self.scheduled.add_query(query, handle.completer());
+        handle
+    }
+
+    /// Queues the update to be run by World::run.
+    pub fn schedule_update<T: Update + Storable>(&mut self, update: T) {
+        self.scheduled.add_update(update);
+    }
+
+    /// Queues the process to be run by World::run.
+    pub fn schedule_process<T: Process + Storable>(&mut self, process: T) {
+        Self::check_access::<T::Reads, T::Writes>();
+        self.scheduled.add_process(process);
+    }
+
+    pub fn schedule_process_simple<T: ProcessSimple + Storable>(&mut self, process: T) {
+        Self::check_access::<T::Reads, T::Writes>();
+        self.scheduled.add_process_simple(process);
+    }
+
+    /// Runs everything that was scheduled. Those which conflict, by writing what another reads or
+    /// writes, run in the order they were scheduled. Those that do not may run together, eg: queries.
+    /// Reading a derived component counts as reading it's inputs.
+    pub fn run(&mut self) {
+        let scheduled = std::mem::take(&mut self.scheduled);
+        scheduled.build_in_order(self).run(self);
+    }
+
+    /// Applies the changes queued in Global<Commands>, in the order they were queued. This is called
+    /// after each process and update, and after each stage of a Schedule, but may also be called directly.
+    pub fn apply_commands(&mut self) {
+        loop {
+            let commands = match self.globals.get_storage::<Global<Commands>>() {
+                Some(storage) => std::mem::take(&mut *storage.get_mut()),
+                None => return,
+            };
+            if commands.is_empty() {
+                return;
+            }
+            // Commands may queue more commands, which are applied after.
+            commands.apply(self);
         }
     }
 }